@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How the case of the generated lines is restored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Case {
+    /// Leave the lines lowercase, like the cleaned training data.
+    #[default]
+    Lower,
+    /// Give every token its most frequent casing in the training lines,
+    /// using how it was written at the start of a line for the first word.
+    Original,
+    /// Keep the casing of names and acronyms, and capitalize the first word
+    /// of every sentence.
+    Sentence,
+}
+
+impl FromStr for Case {
+    type Err = String;
+
+    /// Parse `lower`, `original` or `sentence`.
+    fn from_str(s: &str) -> ::std::result::Result<Case, String> {
+        match s {
+            "lower" => Ok(Case::Lower),
+            "original" => Ok(Case::Original),
+            "sentence" => Ok(Case::Sentence),
+            _ => Err(format!("Unknown case: {}", s)),
+        }
+    }
+}
+
+/// Whether a token ends a sentence. Ellipses don't, as the sentence often
+/// goes on after them.
+fn ends_sentence(token: &str) -> bool {
+    !token.is_empty()
+        && token.chars().all(|c| c == '.' || c == '!' || c == '?')
+        && !token.contains("..")
+}
+
+/// Uppercase the first letter of a word.
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Most frequent of the counted forms, ties broken alphabetically so the
+/// result doesn't depend on the hash map order.
+fn most_frequent(forms: &HashMap<String, i32>) -> Option<&str> {
+    forms
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(form, _)| form.as_str())
+}
+
+/// How each token was written in the training lines, before being
+/// lowercased.
+#[derive(Default)]
+pub struct CaseTable {
+    /// Count of every form of a token id inside a line.
+    pub inner: HashMap<i32, HashMap<String, i32>>,
+    /// Count of every form of a token id starting a line.
+    pub first: HashMap<i32, HashMap<String, i32>>,
+}
+
+impl CaseTable {
+    pub fn new() -> CaseTable {
+        CaseTable::default()
+    }
+
+    /// Count one form of a token, `first` if it started a line.
+    pub fn add(&mut self, id: i32, form: &str, first: bool, count: i32) {
+        let forms = if first {
+            &mut self.first
+        } else {
+            &mut self.inner
+        };
+        *forms
+            .entry(id)
+            .or_default()
+            .entry(form.to_string())
+            .or_insert(0) += count;
+    }
+
+    /// Count the forms of a line, given with the ids of their lowercase
+    /// versions.
+    pub fn add_line(&mut self, ids: &[i32], forms: &[&str]) {
+        for (i, (id, form)) in ids.iter().zip(forms.iter()).enumerate() {
+            self.add(*id, form, i == 0, 1);
+        }
+    }
+
+    /// Every counted form, as `(id, first, form, count)`.
+    pub fn entries(&self) -> impl Iterator<Item = (i32, bool, &str, i32)> {
+        let inner = self.inner.iter().map(|(id, counts)| (id, false, counts));
+        let first = self.first.iter().map(|(id, counts)| (id, true, counts));
+        inner.chain(first).flat_map(|(id, first, counts)| {
+            counts
+                .iter()
+                .map(move |(form, count)| (*id, first, form.as_str(), *count))
+        })
+    }
+
+    /// Add the counts of `other`, its ids going through `remap`. The tokens
+    /// `remap` gives no id to are left out.
+    pub fn merge_remapped<F: Fn(i32) -> Option<i32>>(&mut self, other: &CaseTable, remap: F) {
+        for (id, first, form, count) in other.entries() {
+            if let Some(id) = remap(id) {
+                self.add(id, form, first, count);
+            }
+        }
+    }
+
+    /// Number of distinct forms counted.
+    pub fn len(&self) -> usize {
+        self.inner
+            .values()
+            .chain(self.first.values())
+            .map(|forms| forms.len())
+            .sum()
+    }
+
+    /// Usual casing of a token inside a line. Tokens only seen starting a
+    /// line keep that form, unless it is just capitalized.
+    fn inner_form(&self, id: i32, token: &str) -> String {
+        if let Some(form) = self.inner.get(&id).and_then(most_frequent) {
+            return form.to_string();
+        }
+
+        match self.first.get(&id).and_then(most_frequent) {
+            Some(form) if form != capitalize(token) => form.to_string(),
+            _ => token.to_string(),
+        }
+    }
+
+    /// Usual casing of a token starting a line.
+    fn first_form(&self, id: i32, token: &str) -> String {
+        match self.first.get(&id).and_then(most_frequent) {
+            Some(form) => form.to_string(),
+            None => self.inner_form(id, token),
+        }
+    }
+
+    /// Restore the case of a line of token ids, `tokens` being the lowercase
+    /// vocabulary.
+    pub fn restore(&self, line: &[i32], tokens: &[String], case: Case) -> Vec<String> {
+        let mut sentence_start = true;
+        let mut words = Vec::with_capacity(line.len());
+        for (i, id) in line.iter().enumerate() {
+            let token = tokens[*id as usize].as_str();
+            let word = match case {
+                Case::Lower => token.to_string(),
+                Case::Original if i == 0 => self.first_form(*id, token),
+                Case::Original => self.inner_form(*id, token),
+                Case::Sentence if sentence_start => capitalize(&self.inner_form(*id, token)),
+                Case::Sentence => self.inner_form(*id, token),
+            };
+
+            // Leading quotes or brackets don't start the sentence.
+            if token.chars().any(|c| c.is_alphanumeric()) {
+                sentence_start = false;
+            }
+            if ends_sentence(token) {
+                sentence_start = true;
+            }
+            words.push(word);
+        }
+
+        words
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cases_are_restored() {
+        let tokens: Vec<String> = ["greetings", "to", "amiga", "fans", "!", "the", "end"]
+            .iter()
+            .map(|token| token.to_string())
+            .collect();
+        let mut table = CaseTable::new();
+        table.add_line(&[0, 1, 2, 3, 4], &["Greetings", "to", "Amiga", "fans", "!"]);
+        table.add_line(&[0, 2], &["GREETINGS", "AMIGA"]);
+        table.add_line(&[5, 2, 6], &["The", "Amiga", "END"]);
+        table.add_line(&[5, 6], &["The", "end"]);
+
+        let line = [0, 1, 2, 3, 4, 5, 6];
+        let restore = |case| table.restore(&line, &tokens, case).join(" ");
+        assert_eq!(restore(Case::Lower), "greetings to amiga fans ! the end");
+        assert_eq!(restore(Case::Original), "GREETINGS to Amiga fans ! the END");
+        assert_eq!(restore(Case::Sentence), "GREETINGS to Amiga fans ! The END");
+    }
+
+    #[test]
+    fn ellipses_do_not_end_sentences() {
+        let tokens: Vec<String> = ["great", "...", "really", "?!", "yes"]
+            .iter()
+            .map(|token| token.to_string())
+            .collect();
+        let table = CaseTable::new();
+
+        let line = table.restore(&[0, 1, 2, 3, 4], &tokens, Case::Sentence);
+        assert_eq!(line.join(" "), "Great ... really ?! Yes");
+    }
+}
@@ -1,10 +1,15 @@
 use csv::ReaderBuilder;
 use regex::Regex;
 
-use markovchain::MarkovChain;
-use std::collections::HashMap;
+use detokenizer;
+use markovchain::{MarkovChain, Section};
+use novelty::NoveltyIndex;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
+use std::str::FromStr;
 
 use std::time::Instant;
 
@@ -13,15 +18,55 @@ fn get_fract_s(date: Instant) -> String {
     format!("{}.{:0>3}", duration.as_secs(), duration.subsec_millis())
 }
 
-/// Make a corpus from the CSV
-pub fn csv_to_corpus(path: &Path) -> Vec<String> {
+/// What the tokens of a chain are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Level {
+    #[default]
+    Word,
+    /// Characters, spaces included, to make up new words.
+    Char,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    /// Parse `word` or `char`.
+    fn from_str(s: &str) -> ::std::result::Result<Level, String> {
+        match s {
+            "word" => Ok(Level::Word),
+            "char" => Ok(Level::Char),
+            _ => Err(format!("Unknown level: {}", s)),
+        }
+    }
+}
+
+/// A row of the oneliner CSV.
+pub struct Oneliner {
+    /// Id of the row.
+    pub id: String,
+    /// Posting date, like `2006-02-14 12:00:00`.
+    pub date: String,
+    /// Nickname of the author.
+    pub nick: String,
+    pub text: String,
+}
+
+impl Oneliner {
+    /// Year of the posting date, if it has one.
+    pub fn year(&self) -> Option<i32> {
+        self.date.get(..4).and_then(|year| year.parse().ok())
+    }
+}
+
+/// Read the oneliners of the CSV, with their metadata.
+pub fn csv_to_oneliners(path: &Path) -> Vec<Oneliner> {
     let fname = path.display();
-    let mut corpus: Vec<String> = Vec::new();
+    let mut oneliners: Vec<Oneliner> = Vec::new();
 
     println!("Reading the CSV... ");
     let now = Instant::now();
 
-    let file = match File::open(&path) {
+    let file = match File::open(path) {
         Err(why) => panic!("{} couldn't be read: {}", fname, why),
         Ok(file) => file,
     };
@@ -45,14 +90,27 @@ pub fn csv_to_corpus(path: &Path) -> Vec<String> {
                         line.push_str(sentence);
                     }
                 }
-                corpus.push(line);
+                oneliners.push(Oneliner {
+                    id: record.get(0).unwrap_or("").to_string(),
+                    date: record.get(1).unwrap_or("").to_string(),
+                    nick: record.get(3).unwrap_or("").to_string(),
+                    text: line,
+                });
             }
         }
     }
 
     println!("CSV file read in {}", get_fract_s(now));
 
-    corpus
+    oneliners
+}
+
+/// Make a corpus from the CSV
+pub fn csv_to_corpus(path: &Path) -> Vec<String> {
+    csv_to_oneliners(path)
+        .into_iter()
+        .map(|oneliner| oneliner.text)
+        .collect()
 }
 
 /// Clean a corpus
@@ -64,7 +122,7 @@ pub fn clean_corpus(corpus: &mut [String]) {
     let thread_num = 4;
 
     // Divide the corpus by the number of thread
-    let dist = corpus.len() / thread_num;
+    let dist = cmp::max(1, corpus.len() / thread_num);
 
     let res = crossbeam::scope(|scope| {
         for slice in corpus.chunks_mut(dist) {
@@ -87,34 +145,278 @@ pub fn clean_corpus(corpus: &mut [String]) {
     println!("Corpus cleaned in {}", get_fract_s(now));
 }
 
-/// Parse a oneliner CSV and make it into a markov chain.
-pub fn parse_file(path: &Path) -> MarkovChain {
-    let mut chain = MarkovChain::new();
+/// How the oneliners are sliced by date.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Eras {
+    /// One slice per year.
+    Years,
+    /// Ranges of years, bounds included.
+    Ranges(Vec<(i32, i32)>),
+}
 
-    let mut corpus = csv_to_corpus(path);
-    clean_corpus(&mut corpus);
-    for line in corpus.iter() {
-        get_words(&mut chain, &line);
+impl Eras {
+    /// Sections of the eras a year is part of.
+    fn sections(&self, year: i32) -> Vec<Section> {
+        match self {
+            Eras::Years => vec![Section::Era {
+                from: year,
+                to: year,
+            }],
+            Eras::Ranges(ranges) => ranges
+                .iter()
+                .filter(|(from, to)| *from <= year && year <= *to)
+                .map(|(from, to)| Section::Era {
+                    from: *from,
+                    to: *to,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parse a year, or a range of years like `2002-2007`.
+pub fn parse_years(s: &str) -> ::std::result::Result<(i32, i32), String> {
+    let mut bounds = s.splitn(2, '-').map(|year| year.trim().parse::<i32>());
+    match (bounds.next(), bounds.next()) {
+        (Some(Ok(year)), None) => Ok((year, year)),
+        (Some(Ok(from)), Some(Ok(to))) if from <= to => Ok((from, to)),
+        _ => Err(format!("Invalid years: {}", s)),
     }
+}
+
+impl FromStr for Eras {
+    type Err = String;
+
+    /// Parse `year`, or ranges of years like `2002-2007,2008-2018`.
+    fn from_str(s: &str) -> ::std::result::Result<Eras, String> {
+        if s == "year" {
+            return Ok(Eras::Years);
+        }
+
+        s.split(',')
+            .map(parse_years)
+            .collect::<::std::result::Result<Vec<(i32, i32)>, String>>()
+            .map(Eras::Ranges)
+    }
+}
+
+/// What `parse_file` makes out of the CSV.
+pub struct ParseConfig {
+    /// Number of previous tokens used to pick the next one.
+    pub order: usize,
+    pub level: Level,
+    /// Size of the shingles of the novelty index, if one is wanted.
+    pub novelty: Option<usize>,
+    /// Also train a chain for every author with at least this many lines.
+    pub authors: Option<usize>,
+    /// Also train a chain for every era.
+    pub eras: Option<Eras>,
+    /// Multiply the counts by this factor for every year, so older lines
+    /// weigh less.
+    pub decay: Option<f64>,
+}
+
+impl ParseConfig {
+    /// Empty chain of this config.
+    fn new_chain(&self) -> MarkovChain {
+        let mut chain = MarkovChain::new(self.order);
+        chain.level = self.level;
+        chain.novelty = self.novelty.map(NoveltyIndex::new);
+        chain
+    }
+}
+
+/// Parse a oneliner CSV and make it into a markov chain, with a section per
+/// author or era if asked.
+pub fn parse_file(path: &Path, config: &ParseConfig) -> MarkovChain {
+    let oneliners = csv_to_oneliners(path);
+    let mut oneliners: Vec<&Oneliner> = oneliners.iter().collect();
+    if config.decay.is_some() {
+        oneliners.sort_by_key(|oneliner| oneliner.year());
+    }
+
+    let mut chain = config.new_chain();
+    add_oneliners(
+        &mut chain,
+        &oneliners,
+        config.decay,
+        |oneliner| {
+            let mut sections = Vec::new();
+            if config.authors.is_some() {
+                sections.push(Section::Author(oneliner.nick.clone()));
+            }
+            if let (Some(eras), Some(year)) = (&config.eras, oneliner.year()) {
+                sections.extend(eras.sections(year));
+            }
+            sections
+        },
+        |section, lines| match section {
+            Section::Author(_) => lines >= config.authors.unwrap_or(0),
+            _ => true,
+        },
+    );
 
     chain
 }
 
-/// Get all the words in a oneliner.
-fn get_words(chain: &mut MarkovChain, line: &str) {
-    lazy_static! {
-        static ref split_word_re: Regex = Regex::new(r"\s+").unwrap();
+/// Add the oneliners of a CSV to an existing chain, and to its sections
+/// they belong to. New authors or eras don't get a section. With
+/// `skip_seen`, rows whose id was already added are skipped. Returns the
+/// number of lines added.
+pub fn parse_file_into(chain: &mut MarkovChain, path: &Path, skip_seen: bool) -> usize {
+    let oneliners = csv_to_oneliners(path);
+    let oneliners: Vec<&Oneliner> = oneliners
+        .iter()
+        .filter(|oneliner| !skip_seen || !chain.rows.contains(&oneliner.id))
+        .collect();
+
+    let sections: Vec<Section> = chain.sections.keys().cloned().collect();
+    add_oneliners(
+        chain,
+        &oneliners,
+        None,
+        |oneliner| {
+            sections
+                .iter()
+                .filter(|section| match section {
+                    Section::Author(nick) => *nick == oneliner.nick,
+                    Section::Era { from, to } => oneliner
+                        .year()
+                        .is_some_and(|year| *from <= year && year <= *to),
+                })
+                .cloned()
+                .collect()
+        },
+        |_, _| true,
+    )
+}
+
+/// Clean oneliners and add them to a chain, and to the sections given by
+/// `sections_of` if `keep` accepts their number of lines. With `decay`, the
+/// oneliners are sorted by date and the counts decay between years. Returns
+/// the number of lines added, the filtered ones excluded.
+fn add_oneliners<F, K>(
+    chain: &mut MarkovChain,
+    oneliners: &[&Oneliner],
+    decay: Option<f64>,
+    sections_of: F,
+    keep: K,
+) -> usize
+where
+    F: Fn(&Oneliner) -> Vec<Section>,
+    K: Fn(&Section, usize) -> bool,
+{
+    let originals: Vec<&str> = oneliners.iter().map(|o| o.text.as_str()).collect();
+    let mut corpus: Vec<String> = originals.iter().map(|line| line.to_string()).collect();
+    clean_corpus(&mut corpus);
+
+    let line_sections: Vec<Vec<Section>> = oneliners
+        .iter()
+        .map(|oneliner| sections_of(oneliner))
+        .collect();
+    let mut counts: BTreeMap<&Section, usize> = BTreeMap::new();
+    for (i, sections) in line_sections.iter().enumerate() {
+        if !corpus[i].is_empty() {
+            for section in sections.iter() {
+                *counts.entry(section).or_insert(0) += 1;
+            }
+        }
+    }
+    for (section, lines) in counts.iter() {
+        if keep(section, *lines) && !chain.sections.contains_key(section) {
+            let empty = chain.empty_copy();
+            chain.sections.insert((*section).clone(), empty);
+        }
     }
-    let words: Vec<&str> = split_word_re.split(line).collect();
-    for i in 0..words.len() {
-        let word = words[i].trim().to_string();
 
-        if !chain.tokens.contains(&word) {
-            chain.tokens.push(word.clone());
-            chain.props.push(HashMap::new());
+    let mut added = 0;
+    let mut year: Option<i32> = None;
+    for (i, oneliner) in oneliners.iter().enumerate() {
+        if !oneliner.id.is_empty() {
+            chain.rows.insert(oneliner.id.clone());
+        }
+        if corpus[i].is_empty() {
+            continue;
+        }
+
+        if let (Some(factor), Some(line_year)) = (decay, oneliner.year()) {
+            if let Some(previous) = year.filter(|previous| *previous < line_year) {
+                chain.decay(factor.powi(line_year - previous));
+            }
+            year = Some(line_year);
         }
 
-        let id = (chain.tokens.len() - 1) as i32;
+        add_line(chain, &corpus[i], originals[i]);
+        added += 1;
+        for section in line_sections[i].iter() {
+            if keep(section, counts[section]) {
+                add_line(
+                    chain.sections.get_mut(section).unwrap(),
+                    &corpus[i],
+                    originals[i],
+                );
+            }
+        }
+    }
+
+    added
+}
+
+/// Add a cleaned line and the casing of its original to a chain.
+fn add_line(chain: &mut MarkovChain, line: &str, original: &str) {
+    get_words(chain, line);
+    if !line.is_empty() {
+        get_casing(chain, original);
+    }
+}
+
+/// Split a cleaned line into words.
+pub fn tokenize(line: &str) -> Vec<&str> {
+    lazy_static! {
+        static ref SPLIT_WORD_RE: Regex = Regex::new(r"\s+").unwrap();
+    }
+    let line = line.trim();
+    if line.is_empty() {
+        return Vec::new();
+    }
+
+    SPLIT_WORD_RE.split(line).collect()
+}
+
+/// Text a cleaned line is split from at some level: the line itself for
+/// words, or the line with its usual spacing back for characters.
+pub fn level_line(line: &str, level: Level) -> Cow<'_, str> {
+    match level {
+        Level::Word => Cow::Borrowed(line),
+        Level::Char => Cow::Owned(detokenizer::detokenize(tokenize(line))),
+    }
+}
+
+/// Split a line from `level_line` into tokens.
+pub fn split_level(line: &str, level: Level) -> Vec<&str> {
+    match level {
+        Level::Word => tokenize(line),
+        Level::Char => line
+            .char_indices()
+            .map(|(i, c)| &line[i..i + c.len_utf8()])
+            .collect(),
+    }
+}
+
+/// Get all the tokens in a oneliner.
+pub fn get_words(chain: &mut MarkovChain, line: &str) {
+    let line = level_line(line, chain.level);
+    let words = split_level(&line, chain.level);
+    if words.is_empty() {
+        return;
+    }
+
+    if let Some(ref mut index) = chain.novelty {
+        index.add_line(&words);
+    }
+
+    let ids: Vec<i32> = words.iter().map(|word| chain.add_token(word)).collect();
+    for (i, id) in ids.into_iter().enumerate() {
         if i == 0 && !chain.start.contains(&id) {
             chain.start.push(id);
         }
@@ -123,22 +425,112 @@ fn get_words(chain: &mut MarkovChain, line: &str) {
             chain.end.push(id);
         }
 
-        if i > 0 {
-            chain.add_props(&words[i - 1], &words[i]);
+        // Record the word after every context of length 1 to order, so the
+        // generator can back off when a long context leads nowhere.
+        for from in i.saturating_sub(chain.order)..i {
+            chain.add_props(&words[from..i], words[i]);
+        }
+
+        // And the word before every context starting here, to walk backward.
+        for to in (i + 1)..=cmp::min(i + chain.order, words.len()) {
+            if i == 0 {
+                chain.add_start(&words[i..to]);
+            } else {
+                chain.add_prev(&words[i..to], words[i - 1]);
+            }
         }
     }
+
+    let len = words.len();
+    for from in len.saturating_sub(chain.order)..len {
+        chain.add_end(&words[from..len]);
+    }
 }
 
-/// Clean the text of a line.
-fn clean_line(line: &str) -> String {
+/// Count how the words of a raw line were written before being lowercased.
+/// The line has to be added with `get_words` first.
+pub fn get_casing(chain: &mut MarkovChain, line: &str) {
+    let spaced = space_line(line);
+    let spaced = level_line(&spaced, chain.level);
+    let forms = split_level(&spaced, chain.level);
+    let ids: Option<Vec<i32>> = forms
+        .iter()
+        .map(|form| chain.get_id(&form.to_lowercase()))
+        .collect();
+
+    if let Some(ids) = ids {
+        chain.casing.add_line(&ids, &forms);
+    }
+}
+
+/// Split the quotes around a word, and the end of a contraction like `'s`
+/// or `n't` from its word.
+fn split_quotes(word: &str) -> String {
     lazy_static! {
-        static ref multiple_ponct: Regex = Regex::new(r"(?P<unspaced>[;:\.!\?]+)").unwrap();
+        static ref CLITIC: Regex =
+            Regex::new(r"(?i)^(?P<word>\w+?)(?P<clitic>n't|'(s|m|d|ll|re|ve))$").unwrap();
     }
 
-    let cleaned_line = line.to_lowercase().trim().to_string().replace("\0", "");
-    let rm_mlponct = multiple_ponct.replace_all(&cleaned_line, " $unspaced ");
+    let (open, word) = match word.strip_prefix('\'') {
+        Some(rest) if !rest.is_empty() => ("' ", rest),
+        _ => ("", word),
+    };
+    let (word, close) = match word.strip_suffix('\'') {
+        Some(rest) if !rest.is_empty() => (rest, " '"),
+        _ => (word, ""),
+    };
+    format!("{}{}{}", open, CLITIC.replace(word, "$word $clitic"), close)
+}
 
-    rm_mlponct.to_string()
+/// Clean the text of a line, keeping its case.
+fn space_line(line: &str) -> String {
+    lazy_static! {
+        static ref MULTIPLE_PONCT: Regex = Regex::new(r"(?P<unspaced>[;:,\.!\?]+)").unwrap();
+        static ref BRACKET: Regex = Regex::new(r#"(?P<bracket>["()\[\]{}])"#).unwrap();
+        static ref WORD_EMOTICON: Regex =
+            Regex::new(r"^(?P<emoticon>[:;]['\-^]?(3+|[dpoDPO]))(?P<rest>[;:,\.!\?]*)$").unwrap();
+        static ref SYMBOL_EMOTICON: Regex =
+            Regex::new(r"^(?P<eyes>[:;])(?P<mouth>['\-^]?[()\[\]/\\|*]+)(?P<rest>[;:,\.!\?]*)$")
+                .unwrap();
+    }
+
+    let cleaned_line = line.trim().replace("\0", "");
+    // Emoticons whose mouth could be a word stay whole when they stand alone,
+    // as the colon of `note: d` is no emoticon. The others are split between
+    // their eyes and mouth, which the detokenizer puts back together.
+    cleaned_line
+        .split_whitespace()
+        .map(|chunk| {
+            if let Some(caps) = WORD_EMOTICON.captures(chunk) {
+                format!(
+                    "{} {}",
+                    &caps["emoticon"],
+                    MULTIPLE_PONCT.replace_all(&caps["rest"], " $unspaced ")
+                )
+            } else if let Some(caps) = SYMBOL_EMOTICON.captures(chunk) {
+                format!(
+                    "{} {} {}",
+                    &caps["eyes"],
+                    &caps["mouth"],
+                    MULTIPLE_PONCT.replace_all(&caps["rest"], " $unspaced ")
+                )
+            } else {
+                let spaced = BRACKET.replace_all(chunk, " $bracket ");
+                let spaced = MULTIPLE_PONCT.replace_all(&spaced, " $unspaced ");
+                spaced
+                    .split_whitespace()
+                    .map(split_quotes)
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Clean the text of a line.
+pub fn clean_line(line: &str) -> String {
+    space_line(line).to_lowercase()
 }
 
 /// Filters
@@ -154,24 +546,166 @@ fn ascii_filter(line: &str) -> bool {
 /// Filters oneliner containing urls
 fn url_filter(line: &str) -> bool {
     lazy_static! {
-        static ref url_reg: Regex = Regex::new(r"(.+://.+\.[a-z]+.*$)|(.*www\..*)").unwrap();
+        static ref URL_REG: Regex = Regex::new(r"(.+://.+\.[a-z]+.*$)|(.*www\..*)").unwrap();
     }
-    url_reg.is_match(line)
+    URL_REG.is_match(line)
 }
 
 /// Filters oneliner that don't contain any [a-Z] char.
 fn no_char_filter(line: &str) -> bool {
     lazy_static! {
-        static ref char_reg: Regex = Regex::new(r".*[a-zA-Z]+.*").unwrap();
+        static ref CHAR_REG: Regex = Regex::new(r".*[a-zA-Z]+.*").unwrap();
     }
-    !char_reg.is_match(line)
+    !CHAR_REG.is_match(line)
 }
 
 /// Filter ### bullshit
 fn no_hashtag_bullshit(line: &str) -> bool {
     lazy_static! {
-        static ref bull_reg: Regex = Regex::new(r"^#+").unwrap();
+        static ref BULL_REG: Regex = Regex::new(r"^#+").unwrap();
+    }
+
+    BULL_REG.is_match(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(chain: &MarkovChain, id: i32) -> &str {
+        &chain.tokens[id as usize]
+    }
+
+    #[test]
+    fn start_and_end_ids_match_line_boundaries() {
+        let mut chain = MarkovChain::new(1);
+        get_words(&mut chain, "greetings to all");
+        get_words(&mut chain, "all hail greetings");
+
+        let start: Vec<&str> = chain.start.iter().map(|id| word(&chain, *id)).collect();
+        let end: Vec<&str> = chain.end.iter().map(|id| word(&chain, *id)).collect();
+        assert_eq!(start, vec!["greetings", "all"]);
+        assert_eq!(end, vec!["all", "greetings"]);
+    }
+
+    #[test]
+    fn existing_words_keep_their_id() {
+        let mut chain = MarkovChain::new(1);
+        get_words(&mut chain, "long live amiga");
+        get_words(&mut chain, "amiga long live");
+
+        assert_eq!(chain.tokens, vec!["long", "live", "amiga"]);
+        assert_eq!(chain.get_id("amiga"), Some(2));
+        assert_eq!(chain.get_id("atari"), None);
+    }
+
+    #[test]
+    fn cleaned_punctuation_does_not_create_empty_tokens() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, &clean_line("Awesome!"));
+        get_words(&mut chain, &clean_line(""));
+
+        assert_eq!(chain.tokens, vec!["awesome", "!"]);
+        assert_eq!(word(&chain, chain.end[0]), "!");
+    }
+
+    #[test]
+    fn casing_follows_the_raw_line() {
+        let mut chain = MarkovChain::new(1);
+        let line = "Greetings to TBL!";
+        get_words(&mut chain, &clean_line(line));
+        get_casing(&mut chain, line);
+
+        let tbl = chain.get_id("tbl").unwrap();
+        assert_eq!(chain.casing.inner[&tbl]["TBL"], 1);
+        assert_eq!(chain.casing.first[&0]["Greetings"], 1);
     }
 
-    bull_reg.is_match(line)
+    #[test]
+    fn eras_are_parsed() {
+        assert_eq!("year".parse(), Ok(Eras::Years));
+        assert_eq!(
+            "2002-2007, 2008".parse(),
+            Ok(Eras::Ranges(vec![(2002, 2007), (2008, 2008)]))
+        );
+        assert!("2008-2002".parse::<Eras>().is_err());
+
+        let era = Section::Era {
+            from: 2002,
+            to: 2007,
+        };
+        let eras = Eras::Ranges(vec![(2002, 2007), (2005, 2010)]);
+        assert_eq!(eras.sections(2003), vec![era]);
+        assert_eq!(eras.sections(2005).len(), 2);
+        assert!(eras.sections(2011).is_empty());
+    }
+
+    #[test]
+    fn characters_keep_the_usual_spacing() {
+        let line = clean_line("Hi there :)");
+        let line = level_line(&line, Level::Char);
+        assert_eq!(
+            split_level(&line, Level::Char),
+            vec!["h", "i", " ", "t", "h", "e", "r", "e", " ", ":", ")"]
+        );
+    }
+
+    #[test]
+    fn authors_need_enough_lines_for_a_section() {
+        let path = ::std::env::temp_dir().join("oneliner-authors.csv");
+        ::std::fs::write(
+            &path,
+            "id;date;userid;nick;message\n\
+             1;2005-01-01 12:00:00;8;okkie;long live amiga!\n\
+             2;2005-02-01 12:00:00;8;okkie;amiga rules\n\
+             3;2006-01-01 12:00:00;9;gargaj;Greetings to all\n",
+        )
+        .unwrap();
+
+        let config = ParseConfig {
+            order: 2,
+            level: Level::Word,
+            novelty: None,
+            authors: Some(2),
+            eras: None,
+            decay: None,
+        };
+        let chain = parse_file(&path, &config);
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(chain.rows.len(), 3);
+        assert_eq!(
+            chain.sections.keys().collect::<Vec<&Section>>(),
+            vec![&Section::Author("okkie".to_string())]
+        );
+        let okkie = &chain.sections[&Section::Author("okkie".to_string())];
+        assert_eq!(okkie.start.len(), 2);
+    }
+
+    #[test]
+    fn updates_can_skip_seen_rows() {
+        let path = ::std::env::temp_dir().join("oneliner-update.csv");
+        ::std::fs::write(
+            &path,
+            "id;date;userid;nick;message\n\
+             1;2005-01-01 12:00:00;8;okkie;long live amiga!\n\
+             2;2006-01-01 12:00:00;9;gargaj;Greetings to all\n",
+        )
+        .unwrap();
+
+        let mut chain = MarkovChain::new(1);
+        chain
+            .sections
+            .insert(Section::Author("okkie".to_string()), MarkovChain::new(1));
+        assert_eq!(parse_file_into(&mut chain, &path, true), 2);
+        assert_eq!(parse_file_into(&mut chain, &path, true), 0);
+        assert_eq!(parse_file_into(&mut chain, &path, false), 2);
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(chain.rows.len(), 2);
+        assert_eq!(chain.start.len(), 2);
+        let okkie = &chain.sections[&Section::Author("okkie".to_string())];
+        assert_eq!(okkie.tokens, vec!["long", "live", "amiga", "!"]);
+        assert_eq!(chain.sections.len(), 1);
+    }
 }
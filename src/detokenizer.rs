@@ -0,0 +1,213 @@
+use regex::Regex;
+
+/// Where a token wants spaces around it.
+struct Spacing {
+    before: bool,
+    after: bool,
+}
+
+const SPACED: Spacing = Spacing {
+    before: true,
+    after: true,
+};
+const GLUE_BEFORE: Spacing = Spacing {
+    before: false,
+    after: true,
+};
+const GLUE_AFTER: Spacing = Spacing {
+    before: true,
+    after: false,
+};
+const GLUE_BOTH: Spacing = Spacing {
+    before: false,
+    after: false,
+};
+
+/// Punctuation and ellipses, attached to the previous word.
+fn is_punctuation(token: &str) -> bool {
+    lazy_static! {
+        static ref PUNCTUATION: Regex = Regex::new(r"^[;:,\.!\?]+$").unwrap();
+    }
+    PUNCTUATION.is_match(token)
+}
+
+/// End of a contraction split from its word, like `'s` or `n't`.
+fn is_clitic(token: &str) -> bool {
+    lazy_static! {
+        static ref CLITIC: Regex = Regex::new(r"^(n't|'(s|m|d|ll|re|ve|t))$").unwrap();
+    }
+    CLITIC.is_match(token)
+}
+
+/// Eyes of an emoticon, split from its mouth by `clean_line`.
+fn is_eyes(token: &str) -> bool {
+    token == ":" || token == ";"
+}
+
+/// Mouth of an emoticon, like `)` or `-(`. Mouths that could be words, like
+/// `d` in `:d`, are never split from their eyes by `clean_line`.
+fn is_mouth(token: &str) -> bool {
+    lazy_static! {
+        static ref MOUTH: Regex = Regex::new(r"^['\-^]?[()\[\]/\\|*]+$").unwrap();
+    }
+    MOUTH.is_match(token)
+}
+
+/// `.`, `:` or `,` between two numbers, like in `1.5` or `12:30`.
+fn is_number_separator(token: &str, prev: Option<&str>, next: Option<&str>) -> bool {
+    let separator = token == "." || token == ":" || token == ",";
+    let digit_before = prev.is_some_and(|prev| prev.ends_with(|c: char| c.is_ascii_digit()));
+    let digit_after = next.is_some_and(|next| next.starts_with(|c: char| c.is_ascii_digit()));
+    separator && digit_before && digit_after
+}
+
+/// Glue tokens back into a line, undoing the spacing added by `clean_line`
+/// and putting quotes, brackets, contractions, emoticons and ellipses back
+/// together.
+pub fn detokenize<'a, I>(tokens: I) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let tokens: Vec<&str> = tokens.into_iter().collect();
+    let mut buff = String::new();
+    let mut space = false;
+    let mut double_open = false;
+    let mut single_open = false;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        let prev = if i > 0 { Some(tokens[i - 1]) } else { None };
+        let next = tokens.get(i + 1).cloned();
+
+        if is_eyes(token) && next.is_some_and(is_mouth) {
+            if space {
+                buff.push(' ');
+            }
+            buff.push_str(token);
+            buff.push_str(next.unwrap());
+            space = true;
+            i += 2;
+            continue;
+        }
+
+        let spacing = if is_number_separator(token, prev, next) {
+            GLUE_BOTH
+        } else if token == "\"" {
+            double_open = !double_open;
+            if double_open {
+                GLUE_AFTER
+            } else {
+                GLUE_BEFORE
+            }
+        } else if token == "'" {
+            single_open = !single_open;
+            if single_open {
+                GLUE_AFTER
+            } else {
+                GLUE_BEFORE
+            }
+        } else if token == "(" || token == "[" || token == "{" {
+            GLUE_AFTER
+        } else if token == ")"
+            || token == "]"
+            || token == "}"
+            || is_punctuation(token)
+            || is_clitic(token)
+        {
+            GLUE_BEFORE
+        } else {
+            SPACED
+        };
+
+        if space && spacing.before {
+            buff.push(' ');
+        }
+        buff.push_str(token);
+        space = spacing.after;
+        i += 1;
+    }
+
+    buff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv_parser::{clean_line, tokenize};
+
+    fn round_trip(line: &str) -> String {
+        detokenize(tokenize(&clean_line(line)))
+    }
+
+    #[test]
+    fn punctuation_and_ellipses() {
+        for line in [
+            "greetings to all!",
+            "who is going to revision this year?",
+            "nice prod, great music.",
+            "wait for it... ok",
+            "what?! no way!!!",
+            "party starts at 12:30, version 1.5 is out.",
+        ]
+        .iter()
+        {
+            assert_eq!(&round_trip(line), line);
+        }
+    }
+
+    #[test]
+    fn quotes_and_brackets() {
+        for line in [
+            "he said \"hello\" to me",
+            "best prod (by far) of the party",
+            "the 'real' scene [ok]",
+            "don't stop, it's great",
+        ]
+        .iter()
+        {
+            assert_eq!(&round_trip(line), line);
+        }
+        assert_eq!(
+            tokenize(&clean_line("He said \"hi\" (twice), don't 'stop'")),
+            vec![
+                "he", "said", "\"", "hi", "\"", "(", "twice", ")", ",", "do", "n't", "'", "stop",
+                "'"
+            ]
+        );
+        assert_eq!(
+            tokenize(&clean_line("it's [ok]")),
+            vec!["it", "'s", "[", "ok", "]"]
+        );
+        assert_eq!(
+            detokenize(vec!["he", "said", "\"", "hi", "\"", "!"]),
+            "he said \"hi\"!"
+        );
+        assert_eq!(detokenize(vec!["(", "nice", ")"]), "(nice)");
+        assert_eq!(detokenize(vec!["it", "'s", "ok"]), "it's ok");
+    }
+
+    #[test]
+    fn emoticons() {
+        for line in [
+            "this prod rocks :)",
+            "what a great demo :D",
+            "thumb down ;-) really",
+            "xD :P",
+            "so sad :( bye.",
+            "so cute :3",
+            "nice :p!",
+        ]
+        .iter()
+        {
+            assert_eq!(round_trip(line), line.to_lowercase());
+        }
+        assert_eq!(detokenize(vec!["great", "demo", ":", ")"]), "great demo :)");
+        assert_eq!(tokenize(&clean_line(";-) ok")), vec![";", "-)", "ok"]);
+
+        // A colon attached to a word is punctuation.
+        for line in ["rating: 3 stars", "note: d is a letter", "greets: o"].iter() {
+            assert_eq!(&round_trip(line), line);
+        }
+    }
+}
@@ -6,11 +6,19 @@ extern crate csv;
 extern crate rand;
 extern crate regex;
 
+mod casing;
 mod csv_parser;
+mod detokenizer;
 mod markovchain;
+mod mixture;
+mod novelty;
+mod sampling;
 mod serialize;
+mod smoothing;
 
 use clap::{App, Arg, SubCommand};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::path::Path;
 use std::time::{Duration, Instant};
 
@@ -19,6 +27,26 @@ fn get_fract_s(date: Instant) -> String {
     format!("{}.{:0>3}", duration.as_secs(), duration.subsec_millis())
 }
 
+fn print_throughput(label: &str, num: usize, date: Instant) {
+    let duration = date.elapsed();
+    let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9;
+    println!(
+        "Generated {} oneliners {} in {}s ({:.0} oneliners/s)",
+        num,
+        label,
+        get_fract_s(date),
+        num as f64 / secs,
+    );
+}
+
+/// Parse a decay factor, which must be in (0, 1].
+fn parse_decay(value: Option<&str>) -> Option<f64> {
+    value.map(|factor| match factor.parse::<f64>() {
+        Ok(factor) if factor > 0.0 && factor <= 1.0 => factor,
+        _ => panic!("The decay must be a number between 0 and 1: {}", factor),
+    })
+}
+
 fn main() {
     let matches = App::new("Oneliner")
         .version("0.1a")
@@ -30,6 +58,45 @@ fn main() {
                         .short("t")
                         .help("Export to a text file instead of binary."),
                 )
+                .arg(
+                    Arg::with_name("order")
+                        .help("Number of previous words used to pick the next one.")
+                        .short("-n")
+                        .long("order")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("level")
+                        .help("Tokens of the chain, word or char.")
+                        .long("level")
+                        .takes_value(true)
+                        .default_value("word"),
+                )
+                .arg(
+                    Arg::with_name("authors")
+                        .help("Also make a chain for every author with at least this many oneliners.")
+                        .long("authors")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("eras")
+                        .help("Also make a chain for every year (year) or range of years (2002-2007,2008-2018).")
+                        .long("eras")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("decay")
+                        .help("Multiply the counts by this factor for every year, so older oneliners weigh less.")
+                        .long("decay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("novelty")
+                        .help("Index the training lines by runs of this many tokens, to filter copies out when generating.")
+                        .long("novelty")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("CSV_FILE")
                         .help("CSV file to use.")
@@ -45,6 +112,191 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("generate")
+                .usage(
+                    "oneliner generate [OPTIONS] <BIN_FILE> <ONELINER_NUM>\n    \
+                     oneliner generate [OPTIONS] --model <model>... <ONELINER_NUM>",
+                )
+                .arg(
+                    Arg::with_name("BIN_FILE")
+                        .help("Markovchain binary file, left out with --model.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("ONELINER_NUM")
+                        .help("Number of oneliner to generate.")
+                        .required_unless("model")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("model")
+                        .help("Markovchain binary file to mix with the others, with its weight (a.bin:0.7).")
+                        .long("model")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .conflicts_with_all(&["prefix", "keyword", "beam", "author", "era", "score"]),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .help("Seed of the random generator, to get reproducible oneliners.")
+                        .short("-s")
+                        .long("seed")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .help("Words the oneliners have to start with.")
+                        .short("-p")
+                        .long("prefix")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("keyword")
+                        .help("Word the oneliners have to contain.")
+                        .short("-k")
+                        .long("keyword")
+                        .takes_value(true)
+                        .conflicts_with("prefix"),
+                )
+                .arg(
+                    Arg::with_name("temperature")
+                        .help("Below 1 favors likely words, above 1 favors unlikely ones.")
+                        .long("temperature")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("top-k")
+                        .help("Only pick among the K most likely next words.")
+                        .long("top-k")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("top-p")
+                        .help("Only pick among the most likely next words adding up to this probability.")
+                        .long("top-p")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("greedy")
+                        .help("Always pick the most likely next word.")
+                        .long("greedy"),
+                )
+                .arg(
+                    Arg::with_name("smoothing")
+                        .help("backoff, additive[:k], witten-bell or kneser-ney[:discount].")
+                        .long("smoothing")
+                        .takes_value(true)
+                        .default_value("backoff"),
+                )
+                .arg(
+                    Arg::with_name("max-copy")
+                        .help("Retry oneliners copying more consecutive words from the training set.")
+                        .long("max-copy")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-overlap")
+                        .help("Retry oneliners with a bigger share of words copied from the training set.")
+                        .long("max-overlap")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-words")
+                        .help("Minimum number of words of a oneliner.")
+                        .long("min-words")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-words")
+                        .help("Maximum number of words of a oneliner.")
+                        .long("max-words")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-chars")
+                        .help("Minimum number of characters of a oneliner.")
+                        .long("min-chars")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-chars")
+                        .help("Maximum number of characters of a oneliner.")
+                        .long("max-chars")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("beam")
+                        .help("Print the most likely oneliners found by a beam search of this width.")
+                        .long("beam")
+                        .takes_value(true)
+                        .conflicts_with_all(&["prefix", "keyword", "seed"]),
+                )
+                .arg(
+                    Arg::with_name("diversity")
+                        .help("Penalty of the beam search for similar oneliners.")
+                        .long("diversity")
+                        .takes_value(true)
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("author")
+                        .help("Imitate an author, the binary has to be parsed with --authors.")
+                        .long("author")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("era")
+                        .help("Imitate the oneliners of a year or range of years, the binary has to be parsed with --eras.")
+                        .long("era")
+                        .takes_value(true)
+                        .conflicts_with("author"),
+                )
+                .arg(
+                    Arg::with_name("score")
+                        .help("Print the log-probability of every oneliner.")
+                        .long("score"),
+                )
+                .arg(
+                    Arg::with_name("case")
+                        .help("lower, original or sentence.")
+                        .long("case")
+                        .takes_value(true)
+                        .default_value("lower"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("perplexity")
+                .arg(
+                    Arg::with_name("unseen")
+                        .help("Share of the probability kept for unseen transitions.")
+                        .short("-u")
+                        .long("unseen")
+                        .takes_value(true)
+                        .default_value("0.001"),
+                )
+                .arg(
+                    Arg::with_name("smoothing")
+                        .help("backoff, additive[:k], witten-bell or kneser-ney[:discount].")
+                        .long("smoothing")
+                        .takes_value(true)
+                        .default_value("backoff"),
+                )
+                .arg(
+                    Arg::with_name("BIN_FILE")
+                        .help("Markovchain binary file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("CSV_FILE")
+                        .help("Held-out CSV file to evaluate.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
                 .arg(
                     Arg::with_name("BIN_FILE")
                         .help("Markovchain binary file.")
@@ -54,10 +306,87 @@ fn main() {
                 .arg(
                     Arg::with_name("ONELINER_NUM")
                         .help("Number of oneliner to generate.")
+                        .default_value("10000")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output binary file, instead of overwriting BIN_FILE.")
+                        .short("-o")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("decay")
+                        .help("Multiply the counts by this factor before adding the new oneliners.")
+                        .long("decay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new-only")
+                        .help("Skip the rows whose id was already parsed.")
+                        .long("new-only"),
+                )
+                .arg(
+                    Arg::with_name("BIN_FILE")
+                        .help("Markovchain binary file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("CSV_FILE")
+                        .help("CSV file with the new oneliners.")
                         .required(true)
                         .index(2),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output binary file.")
+                        .short("-o")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("BIN_FILES")
+                        .help("Markovchain binary files to merge.")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .arg(
+                    Arg::with_name("output")
+                        .help("Output binary file, instead of overwriting BIN_FILE.")
+                        .short("-o")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-count")
+                        .help("Drop the transitions seen less often.")
+                        .long("min-count")
+                        .takes_value(true)
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::with_name("max-tokens")
+                        .help("Keep only the most frequent tokens, the others becoming <unk>.")
+                        .long("max-tokens")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("BIN_FILE")
+                        .help("Markovchain binary file.")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("convert")
                 .arg(
@@ -81,9 +410,41 @@ fn main() {
         let path = Path::new(sub_matches.value_of("CSV_FILE").unwrap());
         let bin_path = Path::new(sub_matches.value_of("OUTPUT").unwrap());
         let to_text = sub_matches.is_present("text");
+        let order: usize = sub_matches.value_of("order").unwrap().parse().unwrap();
+        if order < 1 {
+            panic!("The order must be at least 1");
+        }
+
+        let novelty: Option<usize> = sub_matches
+            .value_of("novelty")
+            .map(|size| size.parse().unwrap());
+        if novelty == Some(0) {
+            panic!("The novelty shingles must be at least 1 token long");
+        }
+        let level = match sub_matches.value_of("level").unwrap().parse() {
+            Ok(level) => level,
+            Err(e) => panic!("{}", e),
+        };
 
+        let authors: Option<usize> = sub_matches
+            .value_of("authors")
+            .map(|min| min.parse().unwrap());
+        let eras = match sub_matches.value_of("eras").map(str::parse) {
+            Some(Ok(eras)) => Some(eras),
+            Some(Err(e)) => panic!("{}", e),
+            None => None,
+        };
+
+        let config = csv_parser::ParseConfig {
+            order,
+            level,
+            novelty,
+            authors,
+            eras,
+            decay: parse_decay(sub_matches.value_of("decay")),
+        };
         let mut now = Instant::now();
-        let mkc = csv_parser::parse_file(path);
+        let mkc = csv_parser::parse_file(path, &config);
         println!("Parsed in {}s", get_fract_s(now),);
 
         now = Instant::now();
@@ -111,6 +472,212 @@ fn main() {
     }
 
     if let Some(sub_matches) = matches.subcommand_matches("generate") {
+        let mut config = sampling::GenerationConfig::default();
+        if let Some(temperature) = sub_matches.value_of("temperature") {
+            config.temperature = temperature.parse().unwrap();
+        }
+        if let Some(top_k) = sub_matches.value_of("top-k") {
+            config.top_k = Some(top_k.parse().unwrap());
+        }
+        if let Some(top_p) = sub_matches.value_of("top-p") {
+            config.top_p = Some(top_p.parse().unwrap());
+        }
+        config.greedy = sub_matches.is_present("greedy");
+        let smoothing: smoothing::Smoothing =
+            match sub_matches.value_of("smoothing").unwrap().parse() {
+                Ok(smoothing) => smoothing,
+                Err(e) => panic!("{}", e),
+            };
+        if let Some(max_copy) = sub_matches.value_of("max-copy") {
+            config.max_copy = Some(max_copy.parse().unwrap());
+        }
+        if let Some(max_overlap) = sub_matches.value_of("max-overlap") {
+            config.max_overlap = Some(max_overlap.parse().unwrap());
+        }
+        if let Some(min_words) = sub_matches.value_of("min-words") {
+            config.min_words = Some(min_words.parse().unwrap());
+        }
+        if let Some(max_words) = sub_matches.value_of("max-words") {
+            config.max_words = Some(max_words.parse().unwrap());
+        }
+        if let Some(min_chars) = sub_matches.value_of("min-chars") {
+            config.min_chars = Some(min_chars.parse().unwrap());
+        }
+        if let Some(max_chars) = sub_matches.value_of("max-chars") {
+            config.max_chars = Some(max_chars.parse().unwrap());
+        }
+        match sub_matches.value_of("case").unwrap().parse() {
+            Ok(case) => config.case = case,
+            Err(e) => panic!("{}", e),
+        }
+        let filter_copies = config.max_copy.is_some() || config.max_overlap.is_some();
+
+        // With --model the only positional is the number of oneliners.
+        let num = match (
+            sub_matches.is_present("model"),
+            sub_matches.value_of("ONELINER_NUM"),
+        ) {
+            (true, Some(_)) => panic!("BIN_FILE can't be used with --model"),
+            (true, None) => sub_matches.value_of("BIN_FILE"),
+            (false, num) => num,
+        };
+        let num: usize = num.unwrap().parse().unwrap();
+        let mut rng = match sub_matches.value_of("seed") {
+            Some(seed) => StdRng::seed_from_u64(seed.parse().unwrap()),
+            None => StdRng::from_rng(rand::thread_rng()).unwrap(),
+        };
+
+        if let Some(models) = sub_matches.values_of("model") {
+            let now = Instant::now();
+            let mut chains: Vec<(markovchain::MarkovChain, f64)> = Vec::new();
+            for model in models {
+                let (path, weight) = match model.rfind(':') {
+                    Some(i) if model[i + 1..].parse::<f64>().is_ok() => {
+                        (&model[..i], model[i + 1..].parse().unwrap())
+                    }
+                    _ => (model, 1.0),
+                };
+                match markovchain::MarkovChain::from_binary(Path::new(path)) {
+                    Ok(chain) => chains.push((chain, weight)),
+                    Err(e) => panic!("Could not load {}: {}", path, e),
+                }
+            }
+            let mut mixture = match mixture::MixtureChain::new(chains) {
+                Ok(mixture) => mixture,
+                Err(e) => panic!("{}", e),
+            };
+            println!("Unserialized the models in {}s", get_fract_s(now));
+
+            mixture.config = config;
+            mixture.set_smoothing(smoothing);
+            if filter_copies && !mixture.has_novelty() {
+                println!(
+                    "No model has a novelty index, parse them with --novelty to filter copies."
+                );
+            }
+
+            for _ in 0..num {
+                match mixture.generate_with_rng(&mut rng) {
+                    Ok(oneliner) => println!("{}", oneliner),
+                    Err(e) => panic!("Could not generate: {}", e),
+                }
+                println!("--------------------------------------------------")
+            }
+            return;
+        }
+
+        let bin_path = Path::new(sub_matches.value_of("BIN_FILE").unwrap());
+
+        let now = Instant::now();
+        let mut mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        println!(
+            "Unserialized binary from {} in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
+        if let Some(nick) = sub_matches.value_of("author") {
+            let section = markovchain::Section::Author(nick.to_string());
+            mkc = match mkc.take_section(&section) {
+                Some(chain) => chain,
+                None => panic!("The binary has no {}", section),
+            };
+        }
+        if let Some(era) = sub_matches.value_of("era") {
+            let section = match csv_parser::parse_years(era) {
+                Ok((from, to)) if from == to => mkc.era_of(from),
+                Ok((from, to)) => Some(markovchain::Section::Era { from, to }),
+                Err(e) => panic!("{}", e),
+            };
+            mkc = match section.and_then(|section| mkc.take_section(&section)) {
+                Some(chain) => chain,
+                None => panic!("The binary has no era {}", era),
+            };
+        }
+
+        mkc.config = config;
+        mkc.set_smoothing(smoothing);
+        if filter_copies && mkc.novelty.is_none() {
+            println!("The binary has no novelty index, parse it with --novelty to filter copies.");
+        }
+
+        if let Some(width) = sub_matches.value_of("beam") {
+            let diversity: f64 = sub_matches.value_of("diversity").unwrap().parse().unwrap();
+            for (oneliner, score) in mkc.beam_search(width.parse().unwrap(), num, diversity) {
+                println!("{}", oneliner);
+                println!("score: {:.3}", score);
+                println!("--------------------------------------------------")
+            }
+            return;
+        }
+
+        let prefix = sub_matches.value_of("prefix");
+        let keyword = sub_matches.value_of("keyword");
+
+        for _ in 0..num {
+            let oneliner = if let Some(prefix) = prefix {
+                match mkc.generate_from(prefix, &mut rng) {
+                    Ok(oneliner) => oneliner,
+                    Err(e) => panic!("Could not generate from the prefix: {}", e),
+                }
+            } else if let Some(keyword) = keyword {
+                match mkc.generate_around(keyword, &mut rng) {
+                    Ok(oneliner) => oneliner,
+                    Err(e) => panic!("Could not generate around the keyword: {}", e),
+                }
+            } else {
+                match mkc.generate_with_rng(&mut rng) {
+                    Ok(oneliner) => oneliner,
+                    Err(e) => panic!("Could not generate: {}", e),
+                }
+            };
+            println!("{}", oneliner);
+            if sub_matches.is_present("score") {
+                println!("log-probability: {:.3}", mkc.score(&oneliner));
+            }
+            println!("--------------------------------------------------")
+        }
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("perplexity") {
+        let bin_path = Path::new(sub_matches.value_of("BIN_FILE").unwrap());
+        let path = Path::new(sub_matches.value_of("CSV_FILE").unwrap());
+
+        let mut now = Instant::now();
+        let mut mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        mkc.unseen = sub_matches.value_of("unseen").unwrap().parse().unwrap();
+        match sub_matches.value_of("smoothing").unwrap().parse() {
+            Ok(smoothing) => mkc.set_smoothing(smoothing),
+            Err(e) => panic!("{}", e),
+        }
+        println!(
+            "Unserialized binary from {} in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
+
+        let mut corpus = csv_parser::csv_to_corpus(path);
+        csv_parser::clean_corpus(&mut corpus);
+
+        now = Instant::now();
+        let perplexity = match mkc.perplexity(&corpus) {
+            Some(perplexity) => perplexity,
+            None => panic!("No tokens to score in {}", path.to_str().unwrap()),
+        };
+        println!(
+            "Perplexity of {} is {:.3}, computed in {}s",
+            path.to_str().unwrap(),
+            perplexity,
+            get_fract_s(now),
+        );
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("bench") {
         let bin_path = Path::new(sub_matches.value_of("BIN_FILE").unwrap());
         let num: usize = sub_matches
             .value_of("ONELINER_NUM")
@@ -118,41 +685,158 @@ fn main() {
             .parse()
             .unwrap();
 
-        let now = Instant::now();
-        let mkc2 = markovchain::MarkovChain::from_binary(bin_path);
-        if mkc2.is_err() {
-            panic!("Could not load unserialized txt");
+        let mut now = Instant::now();
+        let mut mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        println!(
+            "Unserialized binary from {} and built sampling tables in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
+
+        now = Instant::now();
+        for _ in 0..num {
+            mkc.generate().unwrap();
+        }
+        print_throughput("with sampling tables", num, now);
+
+        // Without the tables every step rebuilds the distribution.
+        mkc.clear_tables();
+        now = Instant::now();
+        for _ in 0..num {
+            mkc.generate().unwrap();
+        }
+        print_throughput("without sampling tables", num, now);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("update") {
+        let bin_path = Path::new(sub_matches.value_of("BIN_FILE").unwrap());
+        let path = Path::new(sub_matches.value_of("CSV_FILE").unwrap());
+        let out_path = sub_matches.value_of("output").map_or(bin_path, Path::new);
+
+        let mut now = Instant::now();
+        let mut mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        println!(
+            "Unserialized binary from {} in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
+
+        if let Some(factor) = parse_decay(sub_matches.value_of("decay")) {
+            mkc.decay(factor);
+        }
+
+        now = Instant::now();
+        let added = csv_parser::parse_file_into(&mut mkc, path, sub_matches.is_present("new-only"));
+        println!("Added {} oneliners in {}s", added, get_fract_s(now));
+
+        now = Instant::now();
+        if mkc.save_binary(out_path).is_err() {
+            panic!("Could not save binary");
         } else {
             println!(
-                "Unserialized binary from {} in {}s",
-                bin_path.to_str().unwrap(),
+                "Binary serialized in {}s in file: {}",
                 get_fract_s(now),
+                out_path.to_str().unwrap()
             );
         }
-        let mkc = mkc2.unwrap();
+    }
 
-        for _ in 0..num {
-            println!("{}", mkc.generate());
-            println!("--------------------------------------------------")
+    if let Some(sub_matches) = matches.subcommand_matches("merge") {
+        let out_path = Path::new(sub_matches.value_of("output").unwrap());
+
+        let mut now = Instant::now();
+        let mut mkc: Option<markovchain::MarkovChain> = None;
+        for path in sub_matches.values_of("BIN_FILES").unwrap() {
+            let other = match markovchain::MarkovChain::from_binary(Path::new(path)) {
+                Ok(other) => other,
+                Err(e) => panic!("Could not load {}: {}", path, e),
+            };
+            match mkc {
+                Some(ref mut mkc) => {
+                    if let Err(e) = mkc.merge(&other) {
+                        panic!("Could not merge {}: {}", path, e);
+                    }
+                }
+                None => mkc = Some(other),
+            }
+        }
+        println!("Merged in {}s", get_fract_s(now));
+
+        now = Instant::now();
+        if mkc.unwrap().save_binary(out_path).is_err() {
+            panic!("Could not save binary");
+        } else {
+            println!(
+                "Binary serialized in {}s in file: {}",
+                get_fract_s(now),
+                out_path.to_str().unwrap()
+            );
         }
     }
 
-    if let Some(sub_matches) = matches.subcommand_matches("convert") {
-        let bin_path = Path::new(sub_matches.value_of("input").unwrap());
-        let text_path = Path::new(sub_matches.value_of("output").unwrap());
+    if let Some(sub_matches) = matches.subcommand_matches("prune") {
+        let bin_path = Path::new(sub_matches.value_of("BIN_FILE").unwrap());
+        let out_path = sub_matches.value_of("output").map_or(bin_path, Path::new);
+        let min_count: i64 = sub_matches.value_of("min-count").unwrap().parse().unwrap();
+        let max_tokens: Option<usize> = sub_matches
+            .value_of("max-tokens")
+            .map(|max| max.parse().unwrap());
 
         let mut now = Instant::now();
-        let mkc2 = markovchain::MarkovChain::from_binary(bin_path);
-        if mkc2.is_err() {
-            panic!("Could not load unserialized txt");
+        let mut mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        println!(
+            "Unserialized binary from {} in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
+
+        now = Instant::now();
+        let (tokens, states) = (mkc.tokens.len(), mkc.states.len());
+        mkc.prune(min_count, max_tokens);
+        println!(
+            "Pruned from {} to {} tokens and {} to {} states in {}s",
+            tokens,
+            mkc.tokens.len(),
+            states,
+            mkc.states.len(),
+            get_fract_s(now)
+        );
+
+        now = Instant::now();
+        if mkc.save_binary(out_path).is_err() {
+            panic!("Could not save binary");
         } else {
             println!(
-                "Unserialized binary from {} in {}s",
-                bin_path.to_str().unwrap(),
+                "Binary serialized in {}s in file: {}",
                 get_fract_s(now),
+                out_path.to_str().unwrap()
             );
         }
-        let mkc = mkc2.unwrap();
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("convert") {
+        let bin_path = Path::new(sub_matches.value_of("input").unwrap());
+        let text_path = Path::new(sub_matches.value_of("output").unwrap());
+
+        let mut now = Instant::now();
+        let mkc = match markovchain::MarkovChain::from_binary(bin_path) {
+            Ok(mkc) => mkc,
+            Err(e) => panic!("Could not load binary: {}", e),
+        };
+        println!(
+            "Unserialized binary from {} in {}s",
+            bin_path.to_str().unwrap(),
+            get_fract_s(now),
+        );
 
         now = Instant::now();
         let wrt_state = mkc.save_txt(text_path);
@@ -1,73 +1,882 @@
 use rand::prelude::*;
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
-use regex::Regex;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 
+use casing::CaseTable;
+use csv_parser::{self, Level};
+use detokenizer;
+use novelty::NoveltyIndex;
+use sampling::{self, AliasTable, GenerationConfig};
 use serialize::errors::{Error, Result};
 use serialize::{Serializable, Unserializable};
+use smoothing::{self, Smoothing};
+
+/// Discount applied to the weights of a shorter context when backing off.
+const BACKOFF_FACTOR: f64 = 0.4;
+
+/// Pseudo token id following the last word of a line.
+pub const END_TOKEN: i32 = -1;
+
+/// Pseudo token id preceding the first word of a line.
+pub const START_TOKEN: i32 = -2;
+
+/// Token standing for the words dropped by `prune`.
+pub const UNKNOWN_TOKEN: &str = "<unk>";
+
+/// First bytes of a binary file.
+const MAGIC: &[u8; 4] = b"OLMC";
+
+/// Version of the binary format, to bump on every change to it.
+const FORMAT_VERSION: i32 = 2;
+
+/// Count of one line once counts are decayed, see `decay`.
+pub const DECAY_UNIT: i64 = 1000;
+
+/// Default share of the probability kept for unseen transitions.
+pub const DEFAULT_UNSEEN: f64 = 0.001;
+
+/// Safety net on the length of a generated line, in bytes.
+pub const MAX_LENGTH: usize = 330;
+
+/// Number of lines generated before giving up on the novelty filter and
+/// the length constraints.
+pub const MAX_TRIES: usize = 50;
+
+/// What the length constraints say about ending the line at some point.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineEnd {
+    Free,
+    Forbidden,
+    Wanted,
+}
+
+impl LineEnd {
+    /// Keep the weights of the end of line only if it is wanted, or of the
+    /// other tokens only if it is forbidden, as long as some are left.
+    pub fn steer(self, weights: &mut Vec<(i32, f64)>) {
+        let can_end = weights
+            .iter()
+            .any(|item| item.0 == END_TOKEN && item.1 > 0.0);
+        let can_go_on = weights
+            .iter()
+            .any(|item| item.0 != END_TOKEN && item.1 > 0.0);
+        match self {
+            LineEnd::Wanted if can_end => weights.retain(|item| item.0 == END_TOKEN),
+            LineEnd::Forbidden if can_go_on => weights.retain(|item| item.0 != END_TOKEN),
+            _ => {}
+        }
+    }
+}
+
+/// Part of the training data with a chain of its own.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    /// Oneliners of an author, by nickname.
+    Author(String),
+    /// Oneliners posted between two years, included.
+    Era { from: i32, to: i32 },
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Section::Author(nick) => write!(f, "author {}", nick),
+            Section::Era { from, to } if from == to => write!(f, "era {}", from),
+            Section::Era { from, to } => write!(f, "era {}-{}", from, to),
+        }
+    }
+}
+
+/// Whether a token is a word rather than punctuation.
+pub fn is_word(token: &str) -> bool {
+    token.chars().any(|c| c.is_alphanumeric())
+}
+
+/// New id of a token, `ids` giving the new id of every old one. Pseudo
+/// tokens keep their negative id.
+fn remap_id(ids: &[i32], id: i32) -> i32 {
+    if id < 0 {
+        id
+    } else {
+        ids[id as usize]
+    }
+}
+
+/// What turns token ids into lines, shared by the chains that generate
+/// them.
+pub trait LineModel {
+    fn level(&self) -> Level;
+    fn tokens(&self) -> &[String];
+    fn casing(&self) -> &CaseTable;
+    fn config(&self) -> &GenerationConfig;
+    /// Whether a generated line copies too much of the training lines.
+    fn is_copy(&self, line: &[i32]) -> bool;
+
+    /// Length of a token in bytes, counting the space after a word.
+    fn token_length(&self, id: i32) -> usize {
+        let length = self.tokens()[id as usize].len();
+        match self.level() {
+            Level::Word => length + 1,
+            Level::Char => length,
+        }
+    }
+
+    /// Length of a line in bytes.
+    fn length(&self, line: &[i32]) -> usize {
+        line.iter().map(|id| self.token_length(*id)).sum()
+    }
+
+    /// Whether a token following `prev` starts a word. Words are the tokens
+    /// that are not only punctuation, or runs of letters and digits for
+    /// characters.
+    fn starts_word(&self, prev: Option<i32>, id: i32) -> bool {
+        let tokens = self.tokens();
+        let token = &tokens[id as usize];
+        match self.level() {
+            Level::Word => is_word(token),
+            Level::Char => {
+                is_word(token) && !prev.is_some_and(|prev| is_word(&tokens[prev as usize]))
+            }
+        }
+    }
+
+    /// Number of words of a line.
+    fn word_count(&self, line: &[i32]) -> usize {
+        (0..line.len())
+            .filter(|i| self.starts_word(i.checked_sub(1).map(|prev| line[prev]), line[*i]))
+            .count()
+    }
+
+    /// Make a line out of token ids, in the case asked by the config.
+    fn join(&self, line: &[i32]) -> String {
+        let words = self
+            .casing()
+            .restore(line, self.tokens(), self.config().case);
+        match self.level() {
+            Level::Word => detokenizer::detokenize(words.iter().map(|word| word.as_str())),
+            Level::Char => words.concat(),
+        }
+    }
+
+    /// Whether a line of `words` words should end to respect the length
+    /// constraints. Characters are counted like `fits` does, on the joined
+    /// line.
+    fn line_end(&self, line: &[i32], words: usize) -> LineEnd {
+        let config = self.config();
+        // Joining is only worth it when characters are constrained.
+        let chars = if config.min_chars.is_some() || config.max_chars.is_some() {
+            self.join(line).chars().count()
+        } else {
+            0
+        };
+
+        if config.max_words.is_some_and(|max| words >= max)
+            || config.max_chars.is_some_and(|max| chars >= max)
+        {
+            LineEnd::Wanted
+        } else if config.min_words.is_some_and(|min| words < min)
+            || config.min_chars.is_some_and(|min| chars < min)
+        {
+            LineEnd::Forbidden
+        } else {
+            LineEnd::Free
+        }
+    }
+
+    /// Whether a generated line has the length asked by the config.
+    fn fits(&self, line: &[i32]) -> bool {
+        self.config()
+            .fits(self.word_count(line), self.join(line).chars().count())
+    }
+
+    /// Call `walk` until it makes a line that passes the novelty filter and
+    /// the length constraints, giving up after `MAX_TRIES` lines.
+    fn filtered_line<R, F>(&self, rng: &mut R, mut walk: F) -> Result<String>
+    where
+        R: Rng,
+        F: FnMut(&mut R) -> Vec<i32>,
+    {
+        for _ in 0..MAX_TRIES {
+            let line = walk(rng);
+            if self.fits(&line) && !self.is_copy(&line) {
+                return Ok(self.join(&line));
+            }
+        }
+
+        Err(Error::new_no_line_found(MAX_TRIES))
+    }
+}
 
-#[derive(Default)]
 pub struct MarkovChain {
+    /// Number of previous words used to pick the next one.
+    pub order: usize,
+    /// Whether the tokens are words or characters.
+    pub level: Level,
     pub tokens: Vec<String>,
-    pub props: Vec<HashMap<i32, i32>>,
+    /// Contexts of one to `order` token ids. Every suffix of a context is
+    /// stored too, so generation can back off to a shorter one.
+    pub states: Vec<Vec<i32>>,
+    /// Following tokens of each state, indexed like `states`.
+    pub props: Vec<HashMap<i32, i64>>,
+    /// Preceding tokens of each state, indexed like `states`.
+    pub rev_props: Vec<HashMap<i32, i64>>,
+    /// Count added by one line: 1, or `DECAY_UNIT` once decayed so counts
+    /// are fixed-point.
+    pub unit: i64,
     pub start: Vec<i32>,
     pub end: Vec<i32>,
+    /// Index of the training lines, to filter out copies of them.
+    pub novelty: Option<NoveltyIndex>,
+    /// Original casing of the tokens.
+    pub casing: CaseTable,
+    /// Ids of the CSV rows the chain was trained on.
+    pub rows: HashSet<String>,
+    /// Chains trained on parts of the training data only.
+    pub sections: BTreeMap<Section, MarkovChain>,
+    /// How tokens are picked by `generate`. Not saved with the chain.
+    pub config: GenerationConfig,
+    /// Share of the probability kept for unseen transitions when scoring.
+    pub unseen: f64,
+    /// How counts are turned into probabilities, see `set_smoothing`.
+    smoothing: Smoothing,
+    /// Number of distinct tokens preceding each context and its follower,
+    /// only built for Kneser-Ney.
+    continuation: HashMap<Vec<i32>, HashMap<i32, i64>>,
+    /// Sampling table of every state, see `build_tables`.
+    tables: Vec<AliasTable>,
+    /// Sampling table of the first token of a line.
+    start_table: Option<AliasTable>,
+    /// Id of every token, to avoid scanning `tokens`.
+    index: HashMap<String, i32>,
+    state_index: HashMap<Vec<i32>, i32>,
 }
 
 impl MarkovChain {
-    pub fn new() -> MarkovChain {
+    pub fn new(order: usize) -> MarkovChain {
         MarkovChain {
+            order,
+            level: Level::default(),
             tokens: Vec::new(),
+            states: Vec::new(),
             start: Vec::new(),
             end: Vec::new(),
+            novelty: None,
+            casing: CaseTable::new(),
+            rows: HashSet::new(),
+            sections: BTreeMap::new(),
             props: Vec::new(),
+            rev_props: Vec::new(),
+            unit: 1,
+            config: GenerationConfig::default(),
+            unseen: DEFAULT_UNSEEN,
+            smoothing: Smoothing::default(),
+            continuation: HashMap::new(),
+            tables: Vec::new(),
+            start_table: None,
+            index: HashMap::new(),
+            state_index: HashMap::new(),
         }
     }
 
-    fn pick_next(&self, token: usize) -> Option<usize> {
-        let prob = &self.props[token];
-        if prob.len() == 0 {
-            return None;
+    /// Get the tokens following (in `props`) or preceding (in `rev_props`) a
+    /// context, if it has been seen.
+    fn successors<'a>(
+        &self,
+        context: &[i32],
+        props: &'a [HashMap<i32, i64>],
+    ) -> Option<&'a HashMap<i32, i64>> {
+        self.state_index
+            .get(context)
+            .map(|state| &props[*state as usize])
+            .filter(|prob| !prob.is_empty())
+    }
+
+    /// Contexts to use to pick the token after `history`, longest first.
+    fn forward_contexts<'a>(&self, history: &'a [i32]) -> Vec<&'a [i32]> {
+        let longest = cmp::min(self.order, history.len());
+        (1..=longest)
+            .rev()
+            .map(|len| &history[history.len() - len..])
+            .collect()
+    }
+
+    /// Contexts to use to pick the token before `future`, longest first.
+    fn backward_contexts<'a>(&self, future: &'a [i32]) -> Vec<&'a [i32]> {
+        let longest = cmp::min(self.order, future.len());
+        (1..=longest).rev().map(|len| &future[..len]).collect()
+    }
+
+    /// Weight of every token that can follow the contexts when scoring, using
+    /// "stupid backoff": tokens seen after the longest matching context keep
+    /// their relative frequency, and tokens only seen after a shorter context
+    /// are discounted by `BACKOFF_FACTOR` for each level we had to back off.
+    fn backoff_weights(&self, contexts: &[&[i32]], props: &[HashMap<i32, i64>]) -> Vec<(i32, f64)> {
+        let mut weights: HashMap<i32, f64> = HashMap::new();
+        let mut discount = 1.0;
+
+        for context in contexts.iter() {
+            let prob = match self.successors(context, props) {
+                Some(prob) => prob,
+                None => continue,
+            };
+
+            let total: i64 = prob.values().sum();
+            for (k, v) in prob.iter() {
+                weights
+                    .entry(*k)
+                    .or_insert(discount * *v as f64 / total as f64);
+            }
+            discount *= BACKOFF_FACTOR;
+        }
+
+        weights.into_iter().collect()
+    }
+
+    /// Count of every token following the longest of the contexts that has
+    /// been seen, to generate with "stupid backoff". A context only followed
+    /// by the end of line is skipped when `line_end` forbids ending there.
+    fn backoff_successors(
+        &self,
+        contexts: &[&[i32]],
+        props: &[HashMap<i32, i64>],
+        line_end: LineEnd,
+    ) -> Vec<(i32, f64)> {
+        let seen: Vec<Vec<(i32, f64)>> = contexts
+            .iter()
+            .filter_map(|context| self.successors(context, props))
+            .map(|prob| {
+                let mut weights: Vec<(i32, f64)> = prob
+                    .iter()
+                    .filter(|(k, _)| self.can_generate(**k))
+                    .map(|(k, v)| (*k, *v as f64))
+                    .collect();
+                // Sorted so a seeded rng gives the same result on every run.
+                weights.sort_by_key(|item| item.0);
+                weights
+            })
+            .filter(|weights| !weights.is_empty())
+            .collect();
+
+        let can_go_on = |weights: &&Vec<(i32, f64)>| {
+            line_end != LineEnd::Forbidden || weights.iter().any(|item| item.0 != END_TOKEN)
+        };
+        seen.iter()
+            .find(can_go_on)
+            .or_else(|| seen.first())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether a token may be generated: anything but `UNKNOWN_TOKEN`, which
+    /// is only there to score the words dropped by `prune`.
+    fn can_generate(&self, id: i32) -> bool {
+        id < 0 || self.tokens[id as usize] != UNKNOWN_TOKEN
+    }
+
+    /// Possible tokens after a context: every word and the end of line.
+    fn outcomes(&self) -> usize {
+        self.tokens.len() + 1
+    }
+
+    /// Probability of `next` following `history` under a smoothing other
+    /// than `Smoothing::Backoff`.
+    fn smoothed(&self, history: &[i32], next: i32) -> f64 {
+        let uniform = 1.0 / self.outcomes() as f64;
+        // Counts in lines, `unit` being 1 for continuation counts.
+        let count = |prob: &HashMap<i32, i64>, unit: i64| {
+            let total: i64 = prob.values().sum();
+            let count = prob.get(&next).cloned().unwrap_or(0);
+            (count as f64 / unit as f64, total as f64 / unit as f64)
+        };
+
+        match self.smoothing {
+            Smoothing::Backoff => unreachable!(),
+            Smoothing::Additive(k) => {
+                for context in self.forward_contexts(history) {
+                    if let Some(prob) = self.successors(context, &self.props) {
+                        let (count, total) = count(prob, self.unit);
+                        return smoothing::additive(count, total, k, self.outcomes());
+                    }
+                }
+                uniform
+            }
+            Smoothing::WittenBell => {
+                let mut p = uniform;
+                for context in self.forward_contexts(history).iter().rev() {
+                    if let Some(prob) = self.successors(context, &self.props) {
+                        let (count, total) = count(prob, self.unit);
+                        p = smoothing::witten_bell(count, total, prob.len(), p);
+                    }
+                }
+                p
+            }
+            Smoothing::KneserNey(discount) => {
+                // The longest context uses the raw counts, the shorter ones
+                // (down to the empty one) the continuation counts.
+                let longest = cmp::min(self.order, history.len());
+                let mut p = uniform;
+                for len in 0..=longest {
+                    let context = &history[history.len() - len..];
+                    let (prob, unit) = if len == longest {
+                        (self.successors(context, &self.props), self.unit)
+                    } else {
+                        let prob = self
+                            .continuation
+                            .get(context)
+                            .filter(|prob| !prob.is_empty());
+                        (prob, 1)
+                    };
+
+                    if let Some(prob) = prob {
+                        let (count, total) = count(prob, unit);
+                        p = smoothing::kneser_ney(count, total, prob.len(), discount, p);
+                    }
+                }
+                p
+            }
+        }
+    }
+
+    /// Weight of every token that can follow `history`, under what the
+    /// length constraints say about ending the line there.
+    fn next_weights(&self, history: &[i32], line_end: LineEnd) -> Vec<(i32, f64)> {
+        if self.smoothing == Smoothing::Backoff {
+            let contexts = self.forward_contexts(history);
+            return self.backoff_successors(&contexts, &self.props, line_end);
+        }
+
+        (0..self.tokens.len() as i32)
+            .chain(Some(END_TOKEN))
+            .filter(|next| self.can_generate(*next))
+            .map(|next| (next, self.smoothed(history, next)))
+            .collect()
+    }
+
+    /// Probability of every token that can follow `history`, or start a
+    /// line if it is empty, `END_TOKEN` included.
+    pub fn distribution(&self, history: &[i32]) -> Vec<(i32, f64)> {
+        self.steered_distribution(history, LineEnd::Free)
+    }
+
+    /// Like `distribution`, backing off from the contexts that can only end
+    /// the line when `line_end` forbids it.
+    pub fn steered_distribution(&self, history: &[i32], line_end: LineEnd) -> Vec<(i32, f64)> {
+        let weights: Vec<(i32, f64)> = if history.is_empty() {
+            self.start
+                .iter()
+                .filter(|id| self.can_generate(**id))
+                .filter_map(|id| {
+                    self.successors(&[*id], &self.rev_props)
+                        .and_then(|prob| prob.get(&START_TOKEN))
+                        .map(|count| (*id, *count as f64))
+                })
+                .collect()
+        } else {
+            self.next_weights(history, line_end)
+        };
+
+        let total: f64 = weights.iter().map(|item| item.1).sum();
+        weights
+            .into_iter()
+            .filter(|item| item.1 > 0.0)
+            .map(|(id, weight)| (id, weight / total))
+            .collect()
+    }
+
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+        self.smoothing = smoothing;
+        self.build_tables();
+    }
+
+    fn build_continuation(&mut self) {
+        let mut continuation: HashMap<Vec<i32>, HashMap<i32, i64>> = HashMap::new();
+        for (state, prob) in self.states.iter().zip(self.props.iter()) {
+            let lower = continuation.entry(state[1..].to_vec()).or_default();
+            for next in prob.keys() {
+                MarkovChain::increment_prop(*next, 1, lower);
+            }
+        }
+        self.continuation = continuation;
+    }
+
+    /// Precompute the sampling table of every state so generation does not
+    /// have to rebuild a distribution at each step, and the counts needed by
+    /// the smoothing. Any later change to the chain drops them.
+    pub fn build_tables(&mut self) {
+        if let Smoothing::KneserNey(_) = self.smoothing {
+            self.build_continuation();
         }
 
-        let mut rng = rand::thread_rng();
-        let mut probvec: Vec<(i32, i32)> = Vec::new();
-        for (k, v) in prob.iter() {
-            probvec.push((k.clone(), v.clone()));
+        self.tables = self
+            .props
+            .iter()
+            .map(|prob| {
+                // Sorted so a seeded rng gives the same result on every run.
+                let mut weights: Vec<(i32, f64)> = prob
+                    .iter()
+                    .filter(|(k, _)| self.can_generate(**k))
+                    .map(|(k, v)| (*k, *v as f64))
+                    .collect();
+                weights.sort_by_key(|item| item.0);
+                AliasTable::new(&weights)
+            })
+            .collect();
+        let first = self.distribution(&[]);
+        self.start_table = if first.is_empty() {
+            None
+        } else {
+            Some(AliasTable::new(&first))
+        };
+    }
+
+    pub fn clear_tables(&mut self) {
+        self.tables.clear();
+        self.start_table = None;
+        self.continuation.clear();
+    }
+
+    /// Era section covering a year, the shortest one if there are several.
+    pub fn era_of(&self, year: i32) -> Option<Section> {
+        self.sections
+            .keys()
+            .filter(|section| match section {
+                Section::Era { from, to } => *from <= year && year <= *to,
+                _ => false,
+            })
+            .min_by_key(|section| match section {
+                Section::Era { from, to } => to - from,
+                _ => 0,
+            })
+            .cloned()
+    }
+
+    /// Take a section out of the chain, ready to generate.
+    pub fn take_section(&mut self, section: &Section) -> Option<MarkovChain> {
+        let mut chain = self.sections.remove(section)?;
+        chain.build_tables();
+        Some(chain)
+    }
+
+    /// Same distribution as `backoff_successors` when the line can end
+    /// freely, using the precomputed table of the longest seen context.
+    fn pick_next_fast<R: Rng>(&self, history: &[i32], rng: &mut R) -> Option<i32> {
+        let state = self
+            .forward_contexts(history)
+            .iter()
+            .filter_map(|context| self.state_index.get(*context))
+            .map(|state| *state as usize)
+            .find(|state| !self.tables[*state].is_empty())?;
+
+        Some(self.tables[state].sample(rng))
+    }
+
+    /// Pick the token following `history`, which may be `END_TOKEN`.
+    fn pick_next<R: Rng>(&self, history: &[i32], line_end: LineEnd, rng: &mut R) -> Option<i32> {
+        if line_end == LineEnd::Free
+            && self.smoothing == Smoothing::Backoff
+            && self.config.is_plain()
+            && self.tables.len() == self.props.len()
+        {
+            return self.pick_next_fast(history, rng);
         }
 
-        Some(probvec.choose_weighted(&mut rng, |item| item.1).unwrap().0 as usize)
+        let mut weights = self.next_weights(history, line_end);
+        line_end.steer(&mut weights);
+        sampling::sample(weights, &self.config, rng)
     }
 
-    pub fn generate(&self) -> String {
-        lazy_static! {
-            static ref end: Regex = Regex::new(r"[;:,\.!\?]+").unwrap();
+    /// Pick the first token of a line, weighted by the number of lines it
+    /// starts. The config doesn't apply, so greedy lines still vary.
+    fn pick_first<R: Rng>(&self, rng: &mut R) -> Option<i32> {
+        if let Some(ref table) = self.start_table {
+            return Some(table.sample(rng));
         }
 
-        let mut buff = String::new();
-        let mut rng = rand::thread_rng();
+        let first = self.distribution(&[]);
+        if first.is_empty() {
+            None
+        } else {
+            Some(AliasTable::new(&first).sample(rng))
+        }
+    }
 
-        let mut current = self.start.choose(&mut rng).unwrap().clone() as usize;
-        buff.push_str(&self.tokens[current]);
-        while buff.len() < 330 {
-            let next_id = self.pick_next(current);
-            if next_id.is_none() {
-                break;
+    /// Pick the token preceding `future`, which may be `START_TOKEN`.
+    fn pick_prev<R: Rng>(&self, future: &[i32], rng: &mut R) -> Option<i32> {
+        let contexts = self.backward_contexts(future);
+        sampling::sample(
+            self.backoff_successors(&contexts, &self.rev_props, LineEnd::Free),
+            &self.config,
+            rng,
+        )
+    }
+
+    pub fn generate(&self) -> Result<String> {
+        self.generate_with_rng(&mut rand::thread_rng())
+    }
+
+    /// Generate a oneliner, drawing every random choice from `rng`. Fails if
+    /// no line passes the novelty filter and the length constraints.
+    pub fn generate_with_rng<R: Rng>(&self, rng: &mut R) -> Result<String> {
+        self.filtered_line(rng, |rng| match self.pick_first(rng) {
+            Some(first) => self.continue_line(vec![first], rng),
+            None => Vec::new(),
+        })
+    }
+
+    /// Generate a oneliner starting with `prefix`. The prefix is cleaned like
+    /// the training data, and all of its words must be in the vocabulary.
+    pub fn generate_from<R: Rng>(&self, prefix: &str, rng: &mut R) -> Result<String> {
+        let history = self.to_ids(prefix)?;
+        if history.is_empty() {
+            return self.generate_with_rng(rng);
+        }
+
+        self.filtered_line(rng, |rng| self.continue_line(history.clone(), rng))
+    }
+
+    /// Generate a oneliner containing `keyword`, growing it backward to the
+    /// start of a line and then forward to its end.
+    pub fn generate_around<R: Rng>(&self, keyword: &str, rng: &mut R) -> Result<String> {
+        let history = self.to_ids(keyword)?;
+        if history.is_empty() {
+            return self.generate_with_rng(rng);
+        }
+
+        self.filtered_line(rng, |rng| {
+            let mut length = self.length(&history);
+            let mut future = history.clone();
+            while length < MAX_LENGTH {
+                let current = match self.pick_prev(&future, rng) {
+                    Some(START_TOKEN) | None => break,
+                    Some(id) => id,
+                };
+
+                length += self.token_length(current);
+                future.insert(0, current);
             }
 
-            current = next_id.unwrap();
-            if !end.is_match(&self.tokens[current]) {
-                buff.push_str(" ");
+            self.continue_line(future, rng)
+        })
+    }
+
+    /// Clean and split some text like the training data and get the id of
+    /// each token.
+    fn to_ids(&self, text: &str) -> Result<Vec<i32>> {
+        let line = csv_parser::clean_line(text);
+        let line = csv_parser::level_line(&line, self.level);
+        let words = csv_parser::split_level(&line, self.level);
+
+        let mut ids: Vec<i32> = Vec::with_capacity(words.len());
+        for word in words {
+            match self.get_id(word) {
+                Some(id) => ids.push(id),
+                None => return Err(Error::new_unknown_token(word)),
             }
-            buff.push_str(&self.tokens[current]);
         }
 
-        buff
+        Ok(ids)
+    }
+
+    /// Walk the chain from the tokens of `start`, yielding them and then
+    /// every generated token until the end of the line. Follows the config
+    /// but not its novelty filter, which needs whole lines.
+    pub fn walk<'a, 'r, R: Rng>(&'a self, start: Vec<i32>, rng: &'r mut R) -> Walk<'a, 'r, R> {
+        Walk {
+            chain: self,
+            rng,
+            length: self.length(&start),
+            words: self.word_count(&start),
+            history: start,
+            yielded: 0,
+            done: false,
+        }
+    }
+
+    /// Generate the rest of a line that starts with `history`.
+    fn continue_line<R: Rng>(&self, history: Vec<i32>, rng: &mut R) -> Vec<i32> {
+        let mut walk = self.walk(history, rng);
+        while walk.next().is_some() {}
+        walk.into_history()
+    }
+
+    /// Find the `count` most likely complete lines with a beam search of
+    /// `width` lines, under the length constraints and novelty filter of the
+    /// config. Every candidate but the best of a line loses `diversity` times
+    /// its rank among its siblings, to get less similar results. Returns the
+    /// lines with their score, the log-probability minus the penalties.
+    pub fn beam_search(&self, width: usize, count: usize, diversity: f64) -> Vec<(String, f64)> {
+        let by_score = |a: &(Vec<i32>, f64), b: &(Vec<i32>, f64)| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(cmp::Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        };
+
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let line_count = self.line_count();
+        let mut beams: Vec<(Vec<i32>, f64)> = self
+            .start
+            .iter()
+            .map(|id| (vec![*id], self.probability(&[], *id, line_count).ln()))
+            .collect();
+        beams.sort_by(by_score);
+        beams.truncate(width);
+
+        let mut finished: Vec<(Vec<i32>, f64)> = Vec::new();
+        while !beams.is_empty() {
+            let mut candidates: Vec<(Vec<i32>, f64)> = Vec::new();
+            for (line, score) in beams.into_iter() {
+                let length = self.length(&line);
+                if length >= MAX_LENGTH {
+                    continue;
+                }
+
+                let line_end = self.line_end(&line, self.word_count(&line));
+                let mut weights = self.next_weights(&line, line_end);
+                weights.retain(|item| item.1 > 0.0);
+                line_end.steer(&mut weights);
+
+                let total: f64 = weights.iter().map(|item| item.1).sum();
+                weights.sort_by(|a, b| {
+                    b.1.partial_cmp(&a.1)
+                        .unwrap_or(cmp::Ordering::Equal)
+                        .then(a.0.cmp(&b.0))
+                });
+                weights.truncate(width);
+
+                for (rank, (next, weight)) in weights.into_iter().enumerate() {
+                    let next_score = score + (weight / total).ln() - diversity * rank as f64;
+                    if next == END_TOKEN {
+                        if self.fits(&line) && !self.is_copy(&line) {
+                            finished.push((line.clone(), next_score));
+                        }
+                    } else {
+                        let mut next_line = line.clone();
+                        next_line.push(next);
+                        candidates.push((next_line, next_score));
+                    }
+                }
+            }
+
+            candidates.sort_by(by_score);
+            candidates.truncate(width);
+            beams = candidates;
+
+            // Scores only go down, so stop once no beam can make it.
+            finished.sort_by(by_score);
+            if finished.len() >= count {
+                let worst = finished[count - 1].1;
+                if beams.iter().all(|beam| beam.1 <= worst) {
+                    break;
+                }
+            }
+        }
+
+        finished.truncate(count);
+        finished
+            .into_iter()
+            .map(|(line, score)| (self.join(&line), score))
+            .collect()
+    }
+
+    /// Number of lines the chain was trained on.
+    fn line_count(&self) -> i64 {
+        self.states
+            .iter()
+            .zip(self.rev_props.iter())
+            .filter(|(state, _)| state.len() == 1)
+            .filter_map(|(_, prob)| prob.get(&START_TOKEN))
+            .sum()
+    }
+
+    /// Probability of `next` following `history`, or of `next` starting a
+    /// line if `history` is empty. Part of the mass is spread uniformly over
+    /// the vocabulary so unseen transitions don't get a null probability.
+    fn probability(&self, history: &[i32], next: i32, line_count: i64) -> f64 {
+        let seen = if history.is_empty() {
+            self.successors(&[next], &self.rev_props)
+                .and_then(|prob| prob.get(&START_TOKEN))
+                .map_or(0.0, |count| *count as f64 / line_count as f64)
+        } else if self.smoothing == Smoothing::Backoff {
+            let contexts = self.forward_contexts(history);
+            let weights = self.backoff_weights(&contexts, &self.props);
+            let total: f64 = weights.iter().map(|item| item.1).sum();
+            weights
+                .iter()
+                .find(|item| item.0 == next)
+                .map_or(0.0, |item| item.1 / total)
+        } else {
+            self.smoothed(history, next)
+        };
+
+        // Every token and the end of line.
+        let possible = (self.tokens.len() + 1) as f64;
+        (1.0 - self.unseen) * seen + self.unseen / possible
+    }
+
+    /// Natural log-probability of a cleaned and split line, and the number of
+    /// predicted tokens including the end of line.
+    fn score_words(&self, words: &[&str], line_count: i64) -> (f64, usize) {
+        let mut history: Vec<i32> = Vec::with_capacity(words.len());
+        let mut score = 0.0;
+        for word in words.iter() {
+            // Unknown words can't match any context, like START_TOKEN, unless
+            // the chain was pruned.
+            let id = self
+                .get_id(word)
+                .or_else(|| self.get_id(UNKNOWN_TOKEN))
+                .unwrap_or(START_TOKEN);
+            score += self.probability(&history, id, line_count).ln();
+            history.push(id);
+        }
+        score += self.probability(&history, END_TOKEN, line_count).ln();
+
+        (score, words.len() + 1)
+    }
+
+    /// Natural log-probability of a line under the chain. The line is cleaned
+    /// like the training data.
+    pub fn score(&self, line: &str) -> f64 {
+        let line = csv_parser::clean_line(line);
+        let line = csv_parser::level_line(&line, self.level);
+        self.score_words(
+            &csv_parser::split_level(&line, self.level),
+            self.line_count(),
+        )
+        .0
+    }
+
+    /// Per token perplexity of an already cleaned corpus. Lower is better.
+    /// `None` if the corpus has no tokens to score.
+    pub fn perplexity(&self, corpus: &[String]) -> Option<f64> {
+        let line_count = self.line_count();
+        let mut score = 0.0;
+        let mut count = 0;
+        for line in corpus.iter() {
+            let line = csv_parser::level_line(line, self.level);
+            let words = csv_parser::split_level(&line, self.level);
+            if words.is_empty() {
+                continue;
+            }
+
+            let (line_score, line_tokens) = self.score_words(&words, line_count);
+            score += line_score;
+            count += line_tokens;
+        }
+
+        if count == 0 {
+            return None;
+        }
+        Some((-score / count as f64).exp())
     }
 
     fn read_header(file: &mut File) -> Result<i32> {
@@ -102,12 +911,12 @@ impl MarkovChain {
         Vec::unserialize(&array_buffer)
     }
 
-    fn read_props(file: &mut File) -> Result<HashMap<i32, i32>> {
+    fn read_props(file: &mut File) -> Result<HashMap<i32, i64>> {
         let mut buf32: [u8; 4] = [0; 4];
         file.read_exact(&mut buf32)?;
 
         let len: usize = i32::unserialize(&buf32)? as usize;
-        let mut buf: Vec<u8> = vec![0; len * 4 * 2];
+        let mut buf: Vec<u8> = vec![0; len * (4 + 8)];
         file.read_exact(&mut buf)?;
 
         HashMap::unserialize(&buf)
@@ -115,38 +924,106 @@ impl MarkovChain {
 
     /// Unserialized a Markov chain from a binary file.
     pub fn from_binary(path: &Path) -> Result<MarkovChain> {
-        let mut tokens: Vec<String> = Vec::new();
-        let mut props: Vec<HashMap<i32, i32>> = Vec::new();
-
         let mut file = File::open(path)?;
-        let counter = MarkovChain::read_header(&mut file).unwrap();
+        let mut magic: [u8; 4] = [0; 4];
+        if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(Error::new_unsupported_format("not a markov chain file"));
+        }
+        let version = MarkovChain::read_header(&mut file)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::new_unsupported_format(&format!(
+                "format version {}, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        let mut chain = MarkovChain::read_chain(&mut file)?;
+        chain.build_tables();
+        Ok(chain)
+    }
+
+    /// Read a chain and its sections, without their sampling tables.
+    fn read_chain(file: &mut File) -> Result<MarkovChain> {
+        let order = MarkovChain::read_header(file)?;
+        if order < 1 {
+            return Err(Error::new_unserialize());
+        }
+
+        let mut chain = MarkovChain::new(order as usize);
+        let counter = MarkovChain::read_header(file)?;
         for _ in 0..counter {
-            let word = MarkovChain::read_entry(&mut file).unwrap();
-            tokens.push(word);
+            let word = MarkovChain::read_entry(file)?;
+            chain.add_token(&word);
         }
 
-        let start: Vec<i32> = MarkovChain::read_array(&mut file).unwrap();
-        let end: Vec<i32> = MarkovChain::read_array(&mut file).unwrap();
+        chain.start = MarkovChain::read_array(file)?;
+        chain.end = MarkovChain::read_array(file)?;
 
-        while let Ok(prop) = MarkovChain::read_props(&mut file) {
-            props.push(prop);
+        let state_count = MarkovChain::read_header(file)?;
+        for _ in 0..state_count {
+            let state = MarkovChain::read_array(file)?;
+            let prop = MarkovChain::read_props(file)?;
+            let rev_prop = MarkovChain::read_props(file)?;
+            chain
+                .state_index
+                .insert(state.clone(), chain.states.len() as i32);
+            chain.states.push(state);
+            chain.props.push(prop);
+            chain.rev_props.push(rev_prop);
         }
 
-        Ok(MarkovChain {
-            tokens,
-            props,
-            start,
-            end,
-        })
+        let shingle_size = MarkovChain::read_header(file)?;
+        if shingle_size > 0 {
+            let mut index = NoveltyIndex::new(shingle_size as usize);
+            index.shingles = MarkovChain::read_array(file)?.into_iter().collect();
+            chain.novelty = Some(index);
+        }
+
+        let form_count = MarkovChain::read_header(file)?;
+        for _ in 0..form_count {
+            let id = MarkovChain::read_header(file)?;
+            let first = MarkovChain::read_header(file)? != 0;
+            let count = MarkovChain::read_header(file)?;
+            let form = MarkovChain::read_entry(file)?;
+            chain.casing.add(id, &form, first, count);
+        }
+
+        chain.level = match MarkovChain::read_header(file)? {
+            0 => Level::Word,
+            1 => Level::Char,
+            _ => return Err(Error::new_unserialize()),
+        };
+        let mut buf64: [u8; 8] = [0; 8];
+        file.read_exact(&mut buf64)?;
+        chain.unit = i64::unserialize(&buf64)?;
+
+        let row_count = MarkovChain::read_header(file)?;
+        for _ in 0..row_count {
+            chain.rows.insert(MarkovChain::read_entry(file)?);
+        }
+
+        let section_count = MarkovChain::read_header(file)?;
+        for _ in 0..section_count {
+            let section = match MarkovChain::read_header(file)? {
+                0 => Section::Author(MarkovChain::read_entry(file)?),
+                1 => Section::Era {
+                    from: MarkovChain::read_header(file)?,
+                    to: MarkovChain::read_header(file)?,
+                },
+                _ => return Err(Error::new_unserialize()),
+            };
+            let section_chain = MarkovChain::read_chain(file)?;
+            chain.sections.insert(section, section_chain);
+        }
+
+        Ok(chain)
     }
 
     /// Save a plain text version of the markov chain data into a file.
     pub fn save_txt(&self, path: &Path) -> Result<()> {
         let buff = self.txt_serialize();
         match File::create(path) {
-            Ok(mut file) => file
-                .write_all(buff.as_bytes())
-                .map_err(|e| Error::new_io_error(e)),
+            Ok(mut file) => file.write_all(buff.as_bytes()).map_err(Error::new_io_error),
             Err(w) => Err(Error::new_io_error(w)),
         }
     }
@@ -155,10 +1032,14 @@ impl MarkovChain {
     pub fn txt_serialize(&self) -> String {
         let mut buff = String::new();
 
+        buff.push_str(&format!("order: {}\n", self.order));
+        buff.push_str(&format!("level: {:?}\n", self.level));
+        buff.push_str(&format!("unit: {}\n", self.unit));
+
         for word in self.tokens.iter() {
             buff.push_str(&format!("{};", word));
         }
-        buff.push_str("\n");
+        buff.push('\n');
 
         buff.push_str("start: [");
         for word in self.start.iter() {
@@ -172,13 +1053,36 @@ impl MarkovChain {
         }
         buff.push_str("]\n");
 
+        if let Some(ref index) = self.novelty {
+            buff.push_str(&format!(
+                "novelty: {} shingles of {} words\n",
+                index.shingles.len(),
+                index.shingle_size
+            ));
+        }
+        buff.push_str(&format!("casing: {} forms\n", self.casing.len()));
+        buff.push_str(&format!("rows: {}\n", self.rows.len()));
+        for (section, chain) in self.sections.iter() {
+            buff.push_str(&format!(
+                "{}: {} tokens, {} states\n",
+                section,
+                chain.tokens.len(),
+                chain.states.len()
+            ));
+        }
+
         for (id, val) in self.props.iter().enumerate() {
-            buff.push_str(&format!("{}: [", id));
+            buff.push_str(&format!("{} {:?}: [", id, self.states[id]));
 
             for (otherid, count) in val.iter() {
                 buff.push_str(&format!("{} -> {}, ", otherid, *count,));
             }
 
+            buff.push_str("] <- [");
+            for (otherid, count) in self.rev_props[id].iter() {
+                buff.push_str(&format!("{} -> {}, ", otherid, *count,));
+            }
+
             buff.push_str("]\n");
         }
 
@@ -187,17 +1091,21 @@ impl MarkovChain {
 
     /// Save a binary version of the markov chain data into a file.
     pub fn save_binary(&self, path: &Path) -> Result<()> {
-        let ser = self.binary_serialize()?;
+        let mut ser: Vec<u8> = MAGIC.to_vec();
+        ser.extend(&FORMAT_VERSION.serialize()?);
+        ser.extend(&self.binary_serialize()?);
         match File::create(path) {
-            Ok(mut bin_file) => bin_file.write_all(&ser).map_err(|e| Error::new_io_error(e)),
+            Ok(mut bin_file) => bin_file.write_all(&ser).map_err(Error::new_io_error),
             Err(w) => Err(Error::new_io_error(w)),
         }
     }
 
-    /// Serialize the markov chain data to a binary format.
+    /// Serialize the markov chain data to a binary format, without the
+    /// header of the files.
     pub fn binary_serialize(&self) -> Result<Vec<u8>> {
         let words_count: i32 = self.tokens.len() as i32;
         let mut ser: Vec<u8> = Vec::new();
+        ser.extend(&(self.order as i32).serialize()?);
         ser.extend(&words_count.serialize()?);
         ser.extend(&self.tokens.serialize()?);
 
@@ -206,30 +1114,1108 @@ impl MarkovChain {
         ser.extend(&(self.end.len() as i32).serialize()?);
         ser.extend(&self.end.serialize()?);
 
-        for val in self.props.iter() {
-            ser.extend(&(val.len() as i32).serialize()?);
-            ser.extend(&val.serialize()?);
+        ser.extend(&(self.states.len() as i32).serialize()?);
+        for (id, state) in self.states.iter().enumerate() {
+            ser.extend(&(state.len() as i32).serialize()?);
+            ser.extend(&state.serialize()?);
+            ser.extend(&(self.props[id].len() as i32).serialize()?);
+            ser.extend(&self.props[id].serialize()?);
+            ser.extend(&(self.rev_props[id].len() as i32).serialize()?);
+            ser.extend(&self.rev_props[id].serialize()?);
+        }
+
+        match self.novelty {
+            Some(ref index) => {
+                let shingles: Vec<i32> = index.shingles.iter().cloned().collect();
+                ser.extend(&(index.shingle_size as i32).serialize()?);
+                ser.extend(&(shingles.len() as i32).serialize()?);
+                ser.extend(&shingles.serialize()?);
+            }
+            None => ser.extend(&0.serialize()?),
+        }
+
+        ser.extend(&(self.casing.len() as i32).serialize()?);
+        for (id, first, form, count) in self.casing.entries() {
+            ser.extend(&id.serialize()?);
+            ser.extend(&(first as i32).serialize()?);
+            ser.extend(&count.serialize()?);
+            ser.extend(&form.to_string().serialize()?);
+        }
+
+        let level: i32 = match self.level {
+            Level::Word => 0,
+            Level::Char => 1,
+        };
+        ser.extend(&level.serialize()?);
+        ser.extend(&self.unit.serialize()?);
+
+        ser.extend(&(self.rows.len() as i32).serialize()?);
+        for row in self.rows.iter() {
+            ser.extend(&row.serialize()?);
+        }
+
+        ser.extend(&(self.sections.len() as i32).serialize()?);
+        for (section, chain) in self.sections.iter() {
+            match section {
+                Section::Author(nick) => {
+                    ser.extend(&0.serialize()?);
+                    ser.extend(&nick.serialize()?);
+                }
+                Section::Era { from, to } => {
+                    ser.extend(&1.serialize()?);
+                    ser.extend(&from.serialize()?);
+                    ser.extend(&to.serialize()?);
+                }
+            }
+            ser.extend(&chain.binary_serialize()?);
         }
 
         Ok(ser)
     }
 
-    fn get_id(&self, word: &str) -> Option<i32> {
-        match self.tokens.iter().position(|x| x == word) {
-            Some(n) => Some(n as i32),
-            None => None,
+    pub fn get_id(&self, word: &str) -> Option<i32> {
+        self.index.get(word).cloned()
+    }
+
+    /// Get the id of a token, adding it to the vocabulary if needed.
+    pub fn add_token(&mut self, word: &str) -> i32 {
+        if let Some(id) = self.get_id(word) {
+            return id;
+        }
+
+        let id = self.tokens.len() as i32;
+        self.tokens.push(word.to_string());
+        self.index.insert(word.to_string(), id);
+        id
+    }
+
+    /// Get the id of a state, creating it if it does not exist yet.
+    fn get_state(&mut self, context: Vec<i32>) -> i32 {
+        if let Some(id) = self.state_index.get(&context) {
+            return *id;
         }
+
+        let id = self.states.len() as i32;
+        self.state_index.insert(context.clone(), id);
+        self.states.push(context);
+        self.props.push(HashMap::new());
+        self.rev_props.push(HashMap::new());
+        id
     }
 
-    fn increment_prop(id: i32, props: &mut HashMap<i32, i32>) {
-        props.entry(id).and_modify(|e| *e += 1).or_insert(1);
+    fn increment_prop(id: i32, unit: i64, props: &mut HashMap<i32, i64>) {
+        props.entry(id).and_modify(|e| *e += unit).or_insert(unit);
     }
 
-    /// Add a following word to a word or increment the number of time it follows it.
-    pub fn add_props(&mut self, word: &str, next: &str) {
-        let id = self.get_id(word).unwrap();
+    fn add_transition(&mut self, context: &[&str], next_id: i32) {
+        let ids: Vec<i32> = context.iter().map(|w| self.get_id(w).unwrap()).collect();
+        let state = self.get_state(ids);
+        MarkovChain::increment_prop(next_id, self.unit, &mut self.props[state as usize]);
+        self.clear_tables();
+    }
+
+    /// Add a following word to a context or increment the number of time it follows it.
+    pub fn add_props(&mut self, context: &[&str], next: &str) {
         let next_id = self.get_id(next).unwrap();
+        self.add_transition(context, next_id);
+    }
+
+    /// Record that a line can end after a context.
+    pub fn add_end(&mut self, context: &[&str]) {
+        self.add_transition(context, END_TOKEN);
+    }
+
+    fn add_reverse_transition(&mut self, context: &[&str], prev_id: i32) {
+        let ids: Vec<i32> = context.iter().map(|w| self.get_id(w).unwrap()).collect();
+        let state = self.get_state(ids);
+        MarkovChain::increment_prop(prev_id, self.unit, &mut self.rev_props[state as usize]);
+    }
+
+    /// Add a preceding word to a context or increment the number of time it precedes it.
+    pub fn add_prev(&mut self, context: &[&str], prev: &str) {
+        let prev_id = self.get_id(prev).unwrap();
+        self.add_reverse_transition(context, prev_id);
+    }
+
+    /// Record that a line can start with a context.
+    pub fn add_start(&mut self, context: &[&str]) {
+        self.add_reverse_transition(context, START_TOKEN);
+    }
+
+    /// Empty chain of the same order and level, with an empty novelty index
+    /// of the same shingle size.
+    pub fn empty_copy(&self) -> MarkovChain {
+        let mut chain = MarkovChain::new(self.order);
+        chain.level = self.level;
+        chain.unit = self.unit;
+        chain.novelty = self
+            .novelty
+            .as_ref()
+            .map(|index| NoveltyIndex::new(index.shingle_size));
+        chain
+    }
+
+    /// Add the counts of another chain of the same order and level, as if
+    /// its lines had been parsed into this one. The novelty index is only
+    /// kept if both chains have one of the same shingle size, as it would
+    /// miss lines otherwise.
+    pub fn merge(&mut self, other: &MarkovChain) -> Result<()> {
+        if other.order != self.order {
+            return Err(Error::new_incompatible_chains("the orders are different"));
+        }
+        if other.level != self.level {
+            return Err(Error::new_incompatible_chains(
+                "words and characters can't be merged",
+            ));
+        }
+
+        // Plain counts are scaled up to the fixed-point ones of a decayed chain.
+        if self.unit < other.unit {
+            self.rescale(other.unit / self.unit);
+        }
+        let scale = self.unit / other.unit;
+
+        let ids: Vec<i32> = other.tokens.iter().map(|t| self.add_token(t)).collect();
+
+        self.add_states(other, &ids, scale);
+
+        for id in other.start.iter().map(|id| remap_id(&ids, *id)) {
+            if !self.start.contains(&id) {
+                self.start.push(id);
+            }
+        }
+        for id in other.end.iter().map(|id| remap_id(&ids, *id)) {
+            if !self.end.contains(&id) {
+                self.end.push(id);
+            }
+        }
+
+        let same_novelty = match (&self.novelty, &other.novelty) {
+            (Some(index), Some(other_index)) => index.shingle_size == other_index.shingle_size,
+            _ => false,
+        };
+        if same_novelty {
+            let shingles = &other.novelty.as_ref().unwrap().shingles;
+            if let Some(ref mut index) = self.novelty {
+                index.shingles.extend(shingles.iter().cloned());
+            }
+        } else {
+            self.novelty = None;
+        }
+
+        self.casing
+            .merge_remapped(&other.casing, |id| Some(remap_id(&ids, id)));
+
+        self.rows.extend(other.rows.iter().cloned());
+        for (section, other_chain) in other.sections.iter() {
+            self.sections
+                .entry(section.clone())
+                .or_insert_with(|| other_chain.empty_copy())
+                .merge(other_chain)?;
+        }
+
+        self.clear_tables();
+        Ok(())
+    }
+
+    /// Multiply every count by `unit`.
+    fn rescale(&mut self, unit: i64) {
+        for props in self.props.iter_mut().chain(self.rev_props.iter_mut()) {
+            for count in props.values_mut() {
+                *count *= unit;
+            }
+        }
+        self.unit *= unit;
+    }
+
+    /// Multiply all the counts by `factor`, so the lines added afterwards
+    /// weigh more. Counts become fixed-point with `DECAY_UNIT` per line
+    /// first, and the transitions decayed to nothing are dropped. Sections
+    /// are decayed the same way.
+    pub fn decay(&mut self, factor: f64) {
+        if self.unit < DECAY_UNIT {
+            self.rescale(DECAY_UNIT / self.unit);
+        }
+        for props in self.props.iter_mut().chain(self.rev_props.iter_mut()) {
+            for count in props.values_mut() {
+                *count = (*count as f64 * factor).round() as i64;
+            }
+            props.retain(|_, count| *count > 0);
+        }
+
+        let start: Vec<i32> = self
+            .start
+            .iter()
+            .cloned()
+            .filter(|id| {
+                self.successors(&[*id], &self.rev_props)
+                    .is_some_and(|prob| prob.contains_key(&START_TOKEN))
+            })
+            .collect();
+        let end: Vec<i32> = self
+            .end
+            .iter()
+            .cloned()
+            .filter(|id| {
+                self.successors(&[*id], &self.props)
+                    .is_some_and(|prob| prob.contains_key(&END_TOKEN))
+            })
+            .collect();
+        self.start = start;
+        self.end = end;
+
+        for section in self.sections.values_mut() {
+            section.decay(factor);
+        }
+        self.clear_tables();
+    }
+
+    /// Drop the transitions seen less than `min_count` times, line starts and
+    /// ends excepted, and keep only the `max_tokens` most frequent tokens,
+    /// the others becoming `UNKNOWN_TOKEN`. Token and state ids are compacted, and the sections
+    /// are pruned the same way.
+    pub fn prune(&mut self, min_count: i64, max_tokens: Option<usize>) {
+        let threshold = min_count * self.unit;
+        // Occurrences of a token are the transitions of its one token context.
+        let mut frequency = vec![0; self.tokens.len()];
+        for (context, props) in self.states.iter().zip(self.props.iter()) {
+            if context.len() == 1 {
+                frequency[context[0] as usize] = props.values().sum::<i64>();
+            }
+        }
+        let mut kept = vec![true; self.tokens.len()];
+        if let Some(max) = max_tokens {
+            let mut by_frequency: Vec<usize> = (0..self.tokens.len()).collect();
+            by_frequency.sort_by(|a, b| frequency[*b].cmp(&frequency[*a]).then(a.cmp(b)));
+            for id in by_frequency.into_iter().skip(max) {
+                kept[id] = false;
+            }
+        }
+
+        let mut chain = self.empty_copy();
+        let ids: Vec<i32> = self
+            .tokens
+            .iter()
+            .zip(kept.iter())
+            .map(|(token, kept)| chain.add_token(if *kept { token } else { UNKNOWN_TOKEN }))
+            .collect();
+
+        // Contexts of dropped words are merged, so counts are summed before
+        // the threshold applies.
+        chain.add_states(self, &ids, 1);
+
+        let contexts = ::std::mem::take(&mut chain.states);
+        let props = ::std::mem::take(&mut chain.props);
+        let rev_props = ::std::mem::take(&mut chain.rev_props);
+        chain.state_index.clear();
+        for ((context, mut prop), mut rev_prop) in contexts.into_iter().zip(props).zip(rev_props) {
+            // Line boundaries are kept so every line can still start and end.
+            prop.retain(|id, count| *id == END_TOKEN || *count >= threshold);
+            rev_prop.retain(|id, count| *id == START_TOKEN || *count >= threshold);
+            if prop.is_empty() && rev_prop.is_empty() {
+                continue;
+            }
+            let state = chain.get_state(context) as usize;
+            chain.props[state] = prop;
+            chain.rev_props[state] = rev_prop;
+        }
+
+        // Only keep the line boundaries of the remaining states.
+        for id in self.start.iter().map(|id| remap_id(&ids, *id)) {
+            let starts = chain
+                .successors(&[id], &chain.rev_props)
+                .is_some_and(|prob| prob.contains_key(&START_TOKEN));
+            if starts && !chain.start.contains(&id) {
+                chain.start.push(id);
+            }
+        }
+        for id in self.end.iter().map(|id| remap_id(&ids, *id)) {
+            let ends = chain
+                .successors(&[id], &chain.props)
+                .is_some_and(|prob| prob.contains_key(&END_TOKEN));
+            if ends && !chain.end.contains(&id) {
+                chain.end.push(id);
+            }
+        }
+
+        chain.casing.merge_remapped(&self.casing, |id| {
+            Some(ids[id as usize]).filter(|_| kept[id as usize])
+        });
+
+        chain.compact_tokens();
+
+        for section in self.sections.values_mut() {
+            section.prune(min_count, max_tokens);
+        }
+
+        chain.novelty = self.novelty.take();
+        chain.rows = ::std::mem::take(&mut self.rows);
+        chain.sections = ::std::mem::take(&mut self.sections);
+        chain.config = self.config.clone();
+        chain.unseen = self.unseen;
+        chain.smoothing = self.smoothing;
+        *self = chain;
+    }
+
+    /// Drop the tokens no state or line boundary refers to anymore, and
+    /// renumber the others.
+    fn compact_tokens(&mut self) {
+        let mut used = vec![false; self.tokens.len()];
+        let followers = self
+            .props
+            .iter()
+            .chain(self.rev_props.iter())
+            .flat_map(|prob| prob.keys());
+        for id in self
+            .states
+            .iter()
+            .flatten()
+            .chain(followers)
+            .chain(self.start.iter())
+            .chain(self.end.iter())
+        {
+            if *id >= 0 {
+                used[*id as usize] = true;
+            }
+        }
+
+        let tokens = ::std::mem::take(&mut self.tokens);
+        self.index.clear();
+        let mut ids: Vec<i32> = vec![START_TOKEN; tokens.len()];
+        for (id, token) in tokens.iter().enumerate().filter(|(id, _)| used[*id]) {
+            ids[id] = self.add_token(token);
+        }
+
+        self.state_index.clear();
+        for (state, context) in self.states.iter_mut().enumerate() {
+            for id in context.iter_mut() {
+                *id = remap_id(&ids, *id);
+            }
+            self.state_index.insert(context.clone(), state as i32);
+        }
+        for prob in self.props.iter_mut().chain(self.rev_props.iter_mut()) {
+            *prob = prob
+                .drain()
+                .map(|(id, count)| (remap_id(&ids, id), count))
+                .collect();
+        }
+        for id in self.start.iter_mut().chain(self.end.iter_mut()) {
+            *id = remap_id(&ids, *id);
+        }
+
+        let casing = ::std::mem::replace(&mut self.casing, CaseTable::new());
+        self.casing.merge_remapped(&casing, |id| {
+            Some(remap_id(&ids, id)).filter(|_| used[id as usize])
+        });
+    }
+
+    /// Add the transitions of every state of `other`, its token ids going
+    /// through `ids` and its counts multiplied by `scale`. States whose
+    /// contexts end up the same are summed.
+    fn add_states(&mut self, other: &MarkovChain, ids: &[i32], scale: i64) {
+        for (other_state, context) in other.states.iter().enumerate() {
+            let state =
+                self.get_state(context.iter().map(|id| remap_id(ids, *id)).collect()) as usize;
+            for (next, count) in other.props[other_state].iter() {
+                *self.props[state].entry(remap_id(ids, *next)).or_insert(0) += count * scale;
+            }
+            for (prev, count) in other.rev_props[other_state].iter() {
+                *self.rev_props[state]
+                    .entry(remap_id(ids, *prev))
+                    .or_insert(0) += count * scale;
+            }
+        }
+    }
+}
+
+impl LineModel for MarkovChain {
+    fn level(&self) -> Level {
+        self.level
+    }
+
+    fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    fn casing(&self) -> &CaseTable {
+        &self.casing
+    }
+
+    fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    fn is_copy(&self, line: &[i32]) -> bool {
+        let index = match self.novelty {
+            Some(ref index) => index,
+            None => return false,
+        };
+
+        let words: Vec<&str> = line
+            .iter()
+            .map(|id| self.tokens[*id as usize].as_str())
+            .collect();
+        self.config.is_copy(index, &words)
+    }
+}
+
+/// Iterator over the tokens of a line, see `MarkovChain::walk`.
+/// Tokens borrow from the chain only, so they can outlive the rng borrow.
+pub struct Walk<'a, 'r, R: 'r + Rng> {
+    chain: &'a MarkovChain,
+    rng: &'r mut R,
+    history: Vec<i32>,
+    /// Number of tokens of `history` already yielded.
+    yielded: usize,
+    /// Length in bytes, for `MAX_LENGTH`.
+    length: usize,
+    words: usize,
+    done: bool,
+}
+
+impl<'a, 'r, R: Rng> Walk<'a, 'r, R> {
+    /// Ids of the tokens of the line so far.
+    pub fn into_history(self) -> Vec<i32> {
+        self.history
+    }
+}
+
+impl<'a, 'r, R: Rng> Iterator for Walk<'a, 'r, R> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let chain = self.chain;
+        if self.yielded < self.history.len() {
+            self.yielded += 1;
+            return Some(&chain.tokens[self.history[self.yielded - 1] as usize]);
+        }
+
+        if self.done || self.length >= MAX_LENGTH {
+            return None;
+        }
+
+        let line_end = chain.line_end(&self.history, self.words);
+        match chain.pick_next(&self.history, line_end, self.rng) {
+            Some(END_TOKEN) | None => {
+                self.done = true;
+                None
+            }
+            Some(id) => {
+                let token = &chain.tokens[id as usize];
+                self.history.push(id);
+                self.yielded += 1;
+                self.length += chain.token_length(id);
+                if chain.starts_word(self.history.iter().rev().nth(1).cloned(), id) {
+                    self.words += 1;
+                }
+                Some(token)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv_parser::get_words;
+    use rand::rngs::StdRng;
+
+    fn chain() -> MarkovChain {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "greetings to all the sceners");
+        get_words(&mut chain, "greetings to the coders and all the musicians");
+        get_words(&mut chain, "all the best to the demoscene");
+        chain
+    }
+
+    #[test]
+    fn same_seed_gives_same_oneliners() {
+        let chain = chain();
+
+        let mut first = StdRng::seed_from_u64(1337);
+        let mut second = StdRng::seed_from_u64(1337);
+        for _ in 0..20 {
+            assert_eq!(
+                chain.generate_with_rng(&mut first).unwrap(),
+                chain.generate_with_rng(&mut second).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn copies_are_never_returned() {
+        let mut chain = MarkovChain::new(2);
+        chain.novelty = Some(NoveltyIndex::new(2));
+        get_words(&mut chain, "greetings to the demoscene");
+        chain.config.max_copy = Some(2);
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let error = chain.generate_with_rng(&mut rng).err().unwrap();
+        assert!(error.to_string().starts_with("No line found"));
+        chain.config.max_copy = Some(4);
+        assert_eq!(
+            chain.generate_with_rng(&mut rng).unwrap(),
+            "greetings to the demoscene"
+        );
+    }
+
+    #[test]
+    fn generation_stops_at_the_end_of_line() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "greetings to all");
+        get_words(&mut chain, "long live amiga");
+        let mut rng = StdRng::seed_from_u64(3);
+
+        for _ in 0..20 {
+            let line = chain.generate_with_rng(&mut rng).unwrap();
+            assert!(
+                line == "greetings to all" || line == "long live amiga",
+                "{}",
+                line
+            );
+            assert!(line.len() < MAX_LENGTH / 10);
+        }
+    }
+
+    #[test]
+    fn line_ends_are_counted_like_words() {
+        let mut chain = MarkovChain::new(2);
+        for _ in 0..3 {
+            get_words(&mut chain, "amiga rules");
+        }
+        get_words(&mut chain, "amiga rules forever");
+        let history = [id(&chain, "amiga"), id(&chain, "rules")];
+
+        assert_eq!(
+            chain.distribution(&history),
+            vec![(END_TOKEN, 0.75), (id(&chain, "forever"), 0.25)]
+        );
+    }
+
+    #[test]
+    fn backoff_only_leaves_dead_ends() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "a b c");
+        get_words(&mut chain, "x b d");
+        let mut rng = StdRng::seed_from_u64(2);
+
+        for _ in 0..200 {
+            let line = chain.generate_with_rng(&mut rng).unwrap();
+            assert!(line == "a b c" || line == "x b d", "{}", line);
+        }
+        let history = [id(&chain, "a"), id(&chain, "b")];
+        assert_eq!(chain.distribution(&history), vec![(id(&chain, "c"), 1.0)]);
+    }
+
+    #[test]
+    fn backoff_leaves_contexts_that_can_only_end() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "a b");
+        get_words(&mut chain, "x b d");
+        let history = [id(&chain, "a"), id(&chain, "b")];
+
+        assert_eq!(
+            chain.next_weights(&history, LineEnd::Free),
+            vec![(END_TOKEN, 1.0)]
+        );
+        assert_eq!(
+            chain.next_weights(&history, LineEnd::Forbidden),
+            vec![(END_TOKEN, 1.0), (id(&chain, "d"), 1.0)]
+        );
+        chain.config.min_words = Some(3);
+        let mut rng = StdRng::seed_from_u64(2);
+        assert_eq!(chain.generate_from("a b", &mut rng).unwrap(), "a b d");
+    }
+
+    #[test]
+    fn fast_sampling_follows_the_weights() {
+        let mut chain = chain();
+        chain.build_tables();
+        let mut rng = StdRng::seed_from_u64(11);
+        let draws = 20000;
+
+        // "all the" is seen, "demoscene the" backs off to "the".
+        for history in [
+            [id(&chain, "all"), id(&chain, "the")],
+            [id(&chain, "demoscene"), id(&chain, "the")],
+        ]
+        .iter()
+        {
+            let mut fast: HashMap<i32, usize> = HashMap::new();
+            let mut slow: HashMap<i32, usize> = HashMap::new();
+            for _ in 0..draws {
+                let next = chain.pick_next_fast(history, &mut rng).unwrap();
+                *fast.entry(next).or_insert(0) += 1;
+                let weights = chain.next_weights(history, LineEnd::Free);
+                let next = sampling::sample(weights, &chain.config, &mut rng).unwrap();
+                *slow.entry(next).or_insert(0) += 1;
+            }
+
+            for (next, probability) in chain.distribution(history) {
+                for counts in [&fast, &slow].iter() {
+                    let frequency = counts.get(&next).cloned().unwrap_or(0) as f64 / draws as f64;
+                    assert!((frequency - probability).abs() < 0.02, "{}", next);
+                }
+            }
+            assert_eq!(fast.len(), slow.len());
+        }
+    }
+
+    #[test]
+    fn walk_yields_the_generated_tokens() {
+        let chain = chain();
+        let start = vec![chain.get_id("greetings").unwrap()];
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let tokens: Vec<&str> = chain.walk(start.clone(), &mut rng).collect();
+        // The tokens only borrow the chain, so the rng is free again.
+        let next: Vec<&str> = chain.walk(start.clone(), &mut rng).collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let line = chain.continue_line(start, &mut rng);
+
+        assert_eq!(tokens[0], "greetings");
+        assert_eq!(next[0], "greetings");
+        assert_eq!(detokenizer::detokenize(tokens), chain.join(&line));
+    }
+
+    #[test]
+    fn generate_from_keeps_the_prefix() {
+        let chain = chain();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let line = chain.generate_from("All  THE", &mut rng).unwrap();
+        assert!(line.starts_with("all the "));
+        assert!(chain.generate_from("all the atari", &mut rng).is_err());
+    }
+
+    #[test]
+    fn seen_lines_score_higher() {
+        let chain = chain();
+
+        let seen = chain.score("Greetings to all the sceners");
+        let unseen = chain.score("sceners the all to greetings");
+        assert!(seen.is_finite() && unseen.is_finite());
+        assert!(seen > unseen);
+        assert!(chain.score("greetings to atari") < seen);
+    }
+
+    #[test]
+    fn perplexity_needs_tokens() {
+        let chain = chain();
+
+        let perplexity = chain.perplexity(&["greetings to all".to_string()]).unwrap();
+        assert!(perplexity.is_finite() && perplexity > 1.0);
+        assert!(chain.perplexity(&[]).is_none());
+        assert!(chain.perplexity(&["".to_string()]).is_none());
+    }
+
+    /// "a b" twice and "a c" once, with order 2.
+    fn tiny_chain(smoothing: Smoothing) -> MarkovChain {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "a b");
+        get_words(&mut chain, "a b");
+        get_words(&mut chain, "a c");
+        chain.set_smoothing(smoothing);
+        chain
+    }
+
+    fn id(chain: &MarkovChain, word: &str) -> i32 {
+        chain.get_id(word).unwrap()
+    }
+
+    #[test]
+    fn smoothed_distributions_sum_to_one() {
+        let smoothings = [
+            Smoothing::Additive(0.5),
+            Smoothing::WittenBell,
+            Smoothing::KneserNey(0.75),
+        ];
+        for smoothing in smoothings.iter() {
+            let chain = tiny_chain(*smoothing);
+            for history in [
+                vec![id(&chain, "a")],
+                vec![id(&chain, "a"), id(&chain, "b")],
+            ]
+            .iter()
+            {
+                let weights = chain.next_weights(history, LineEnd::Free);
+                let total: f64 = weights.iter().map(|item| item.1).sum();
+                assert!((total - 1.0).abs() < 1e-9, "{:?}: {}", smoothing, total);
+                assert!(weights.iter().all(|item| item.1 > 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn laplace_by_hand() {
+        let chain = tiny_chain(Smoothing::Additive(1.0));
+        let a = id(&chain, "a");
+
+        // After "a": b twice, c once, out of 3 words + end of line.
+        assert!((chain.smoothed(&[a], id(&chain, "b")) - 3.0 / 7.0).abs() < 1e-9);
+        assert!((chain.smoothed(&[a], a) - 1.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn witten_bell_by_hand() {
+        let chain = tiny_chain(Smoothing::WittenBell);
+        let a = id(&chain, "a");
+
+        // Two distinct followers of "a" out of 3 transitions, uniform below.
+        let lower = 1.0 / 4.0;
+        let expected = (2.0 + 2.0 * lower) / (3.0 + 2.0);
+        assert!((chain.smoothed(&[a], id(&chain, "b")) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn kneser_ney_by_hand() {
+        let chain = tiny_chain(Smoothing::KneserNey(0.5));
+        let a = id(&chain, "a");
+        let b = id(&chain, "b");
+
+        // Continuation counts of the empty context: b and c follow one
+        // distinct word ("a"), the end of line follows two ("b" and "c").
+        let lower = 0.5 / 4.0 + 0.5 * 3.0 / 4.0 * (1.0 / 4.0);
+        let expected = 1.5 / 3.0 + 0.5 * 2.0 / 3.0 * lower;
+        assert!((chain.smoothed(&[a], b) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lines_have_the_length_asked() {
+        let mut chain = chain();
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut lines = |config: GenerationConfig| {
+            chain.config = config;
+            (0..20)
+                .map(|_| chain.generate_with_rng(&mut rng).unwrap())
+                .collect::<Vec<String>>()
+        };
+        let words = |line: &String| line.split_whitespace().count();
+
+        let config = GenerationConfig {
+            min_words: Some(7),
+            ..GenerationConfig::default()
+        };
+        assert!(lines(config).iter().all(|line| words(line) >= 7));
+        let config = GenerationConfig {
+            max_words: Some(3),
+            ..GenerationConfig::default()
+        };
+        assert!(lines(config).iter().all(|line| words(line) <= 3));
+        let config = GenerationConfig {
+            min_chars: Some(30),
+            ..GenerationConfig::default()
+        };
+        assert!(lines(config).iter().all(|line| line.len() >= 30));
+        let config = GenerationConfig {
+            max_chars: Some(20),
+            ..GenerationConfig::default()
+        };
+        assert!(lines(config).iter().all(|line| line.len() <= 20));
+
+        // Lines out of reach are not returned anyway.
+        chain.config = GenerationConfig {
+            min_words: Some(50),
+            ..GenerationConfig::default()
+        };
+        assert!(chain.generate_with_rng(&mut rng).is_err());
+        chain.config = GenerationConfig {
+            max_chars: Some(5),
+            ..GenerationConfig::default()
+        };
+        assert!(chain.generate_with_rng(&mut rng).is_err());
+    }
+
+    #[test]
+    fn short_lines_go_on_when_they_can() {
+        let mut chain = MarkovChain::new(2);
+        for _ in 0..99 {
+            get_words(&mut chain, "amiga");
+        }
+        get_words(&mut chain, "amiga rules forever");
+        chain.config.min_words = Some(3);
+
+        // Without retries, the end of line is only left out.
+        let amiga = chain.get_id("amiga").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let line: Vec<&str> = chain.walk(vec![amiga], &mut rng).collect();
+            assert_eq!(line, vec!["amiga", "rules", "forever"]);
+        }
+    }
+
+    #[test]
+    fn beam_search_finds_the_most_likely_line() {
+        let chain = tiny_chain(Smoothing::Backoff);
+
+        let best = chain.beam_search(4, 2, 0.0);
+        assert_eq!(best[0].0, "a b");
+        assert_eq!(best[1].0, "a c");
+        assert!(best[0].1 > best[1].1);
+        assert!(chain.beam_search(4, 0, 0.0).is_empty());
+    }
+
+    #[test]
+    fn generate_around_grows_whole_lines() {
+        let chain = chain();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..20 {
+            let line = chain.generate_around("coders", &mut rng).unwrap();
+            let first = chain.get_id(line.split(' ').next().unwrap()).unwrap();
+            assert!(line.contains("coders"));
+            assert!(chain.start.contains(&first));
+        }
+    }
+
+    #[test]
+    fn characters_make_up_words() {
+        let mut chain = MarkovChain::new(3);
+        chain.level = Level::Char;
+        for line in ["farbrausch", "fairlight", "future crew", "razor 1911"].iter() {
+            get_words(&mut chain, line);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let line = chain.generate_from("F", &mut rng).unwrap();
+            assert!(line.starts_with('f'));
+            assert!(!line.contains("  "));
+        }
+
+        let ids = chain.to_ids("Future crew").unwrap();
+        assert_eq!(ids.len(), 11);
+        assert_eq!(chain.length(&ids), 11);
+        assert_eq!(chain.word_count(&ids), 2);
+    }
+
+    #[test]
+    fn order_is_saved_with_the_chain() {
+        let mut chain = MarkovChain::new(3);
+        get_words(&mut chain, "greetings to all the sceners");
+        get_words(&mut chain, "greetings to the coders and all the musicians");
+
+        let path = ::std::env::temp_dir().join("oneliner-order.bin");
+        chain.save_binary(&path).unwrap();
+        let loaded = MarkovChain::from_binary(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.order, 3);
+        assert_eq!(loaded.states, chain.states);
+        assert_eq!(loaded.props, chain.props);
+        assert!(loaded.states.iter().any(|context| context.len() == 3));
+        assert!(loaded.states.iter().all(|context| context.len() <= 3));
+    }
+
+    #[test]
+    fn sections_are_saved_with_the_chain() {
+        let mut chain = chain();
+        let mut author = MarkovChain::new(2);
+        get_words(&mut author, "long live amiga");
+        chain
+            .sections
+            .insert(Section::Author("okkie".to_string()), author);
+
+        let path = ::std::env::temp_dir().join("oneliner-sections.bin");
+        chain.save_binary(&path).unwrap();
+        let mut loaded = MarkovChain::from_binary(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.tokens, chain.tokens);
+        assert!(loaded
+            .take_section(&Section::Author("gargaj".to_string()))
+            .is_none());
+        let author = loaded
+            .take_section(&Section::Author("okkie".to_string()))
+            .unwrap();
+        assert_eq!(author.generate().unwrap(), "long live amiga");
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let path = ::std::env::temp_dir().join("oneliner-version.bin");
+        let mut bytes: Vec<u8> = MAGIC.to_vec();
+        bytes.extend(&(FORMAT_VERSION + 1).serialize().unwrap());
+        bytes.extend(&chain().binary_serialize().unwrap());
+        ::std::fs::write(&path, &bytes).unwrap();
+        let newer = MarkovChain::from_binary(&path).err().unwrap();
+        ::std::fs::write(&path, b"order;tokens").unwrap();
+        let text = MarkovChain::from_binary(&path).err().unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert!(newer.to_string().contains("format version"));
+        assert!(text.to_string().contains("not a markov chain"));
+    }
+
+    #[test]
+    fn merging_is_like_parsing_both() {
+        let lines = [
+            "greetings to all the sceners",
+            "greetings to the coders and all the musicians",
+            "all the best to the demoscene",
+            "long live the amiga",
+        ];
+        let mut all = MarkovChain::new(2);
+        let mut first = MarkovChain::new(2);
+        let mut second = MarkovChain::new(2);
+        for (i, line) in lines.iter().enumerate() {
+            get_words(&mut all, line);
+            get_words(if i < 2 { &mut first } else { &mut second }, line);
+        }
+
+        first.merge(&second).unwrap();
+        assert_eq!(first.tokens.len(), all.tokens.len());
+        assert_eq!(first.states.len(), all.states.len());
+        for line in lines.iter().chain(["the amiga rules"].iter()) {
+            assert!((first.score(line) - all.score(line)).abs() < 1e-9);
+        }
+        assert!(first.merge(&MarkovChain::new(3)).is_err());
+    }
+
+    #[test]
+    fn pruning_keeps_the_chain_consistent() {
+        let mut chain = chain();
+        chain.prune(1, Some(3));
+
+        assert_eq!(chain.tokens, vec!["<unk>", "to", "all", "the"]);
+        for id in chain.start.iter().chain(chain.end.iter()) {
+            assert!((*id as usize) < chain.tokens.len());
+        }
+        assert!(chain.states.iter().flatten().all(|id| *id < 4));
+        assert!(chain.score("all the atari") > chain.score("all the to"));
+
+        chain.prune(2, None);
+        let the = chain.get_id("the").unwrap();
+        let prob = chain.successors(&[the], &chain.props).unwrap();
+        assert!(prob.values().all(|count| *count >= 2));
+        // Line boundaries don't count as rare transitions.
+        assert_eq!(chain.start, vec![0, chain.get_id("all").unwrap()]);
+        assert_eq!(chain.end, vec![0]);
+        assert!(!chain
+            .generate_with_rng(&mut StdRng::seed_from_u64(0))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(MarkovChain::new(2).generate().unwrap(), "");
+    }
+
+    #[test]
+    fn unknown_tokens_are_never_generated() {
+        let mut chain = chain();
+        chain.prune(1, Some(3));
+        chain.build_tables();
+        let unknown = id(&chain, UNKNOWN_TOKEN);
+
+        assert!(chain.distribution(&[]).iter().all(|item| item.0 != unknown));
+        let mut rng = StdRng::seed_from_u64(9);
+        for _ in 0..50 {
+            assert!(!chain
+                .generate_with_rng(&mut rng)
+                .unwrap()
+                .contains(UNKNOWN_TOKEN));
+        }
+        chain.set_smoothing(Smoothing::WittenBell);
+        let the = id(&chain, "the");
+        assert!(chain
+            .distribution(&[the])
+            .iter()
+            .all(|item| item.0 != unknown));
+        assert!(chain.score("all the atari").is_finite());
+    }
+
+    #[test]
+    fn pruning_drops_unused_tokens() {
+        // Words only seen in rare transitions leave the vocabulary.
+        let mut chain = chain();
+        chain.prune(2, None);
+        assert_eq!(chain.get_id("coders"), None);
+        assert_eq!(chain.get_id("and"), None);
+        let size = chain.tokens.len() as i32;
+        assert!(chain.states.iter().flatten().all(|id| *id < size));
+        assert!(chain
+            .start
+            .iter()
+            .chain(chain.end.iter())
+            .all(|id| *id < size));
+    }
+
+    #[test]
+    fn decayed_lines_weigh_less() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "amiga rules");
+        chain.decay(0.5);
+        get_words(&mut chain, "atari rules");
+
+        assert_eq!(chain.unit, DECAY_UNIT);
+        let atari = chain.get_id("atari").unwrap();
+        let first = chain.distribution(&[]);
+        let weight = first.iter().find(|item| item.0 == atari).unwrap().1;
+        assert!((weight - 2.0 / 3.0).abs() < 1e-9);
+
+        // Generation starts lines with the same weights.
+        chain.build_tables();
+        let mut rng = StdRng::seed_from_u64(0);
+        let atari_lines = (0..1000)
+            .filter(|_| {
+                chain
+                    .generate_with_rng(&mut rng)
+                    .unwrap()
+                    .starts_with("atari")
+            })
+            .count();
+        assert!(atari_lines > 620 && atari_lines < 710);
+
+        // Plain counts are scaled when merged into decayed ones.
+        let mut other = MarkovChain::new(2);
+        get_words(&mut other, "amiga rules");
+        chain.merge(&other).unwrap();
+        let weight = chain
+            .distribution(&[])
+            .iter()
+            .find(|item| item.0 == atari)
+            .unwrap()
+            .1;
+        assert!((weight - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pruning_a_decayed_chain_keeps_its_sections() {
+        let mut chain = chain();
+        let mut section = chain.empty_copy();
+        get_words(&mut section, "greetings to all the sceners");
+        let okkie = Section::Author("okkie".to_string());
+        chain.sections.insert(okkie.clone(), section);
+        chain.decay(0.5);
+        let section = chain.sections.get_mut(&okkie).unwrap();
+        get_words(section, "greetings to all the sceners");
+
+        chain.prune(1, None);
+        let section = chain.take_section(&okkie).unwrap();
+        assert_eq!(section.unit, DECAY_UNIT);
+        assert!(!section.states.is_empty());
+        assert_eq!(section.distribution(&[]).len(), 1);
+    }
+
+    #[test]
+    fn counts_can_exceed_32_bits() {
+        let mut chain = MarkovChain::new(2);
+        get_words(&mut chain, "amiga rules");
+        get_words(&mut chain, "atari rules");
+        chain.decay(1.0);
+        // As if three million lines had started with "amiga".
+        let amiga = chain.get_id("amiga").unwrap();
+        let state = chain.state_index[&vec![amiga]] as usize;
+        chain.rev_props[state].insert(START_TOKEN, 3_000_000 * DECAY_UNIT);
+        get_words(&mut chain, "amiga rules");
+
+        let path = ::std::env::temp_dir().join("oneliner-counts.bin");
+        chain.save_binary(&path).unwrap();
+        let loaded = MarkovChain::from_binary(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
 
-        MarkovChain::increment_prop(next_id, &mut self.props[id as usize]);
+        assert_eq!(loaded.line_count(), 3_000_002 * DECAY_UNIT);
+        let first = loaded.distribution(&[]);
+        let weight = first.iter().find(|item| item.0 == amiga).unwrap().1;
+        assert!((weight - 3_000_001.0 / 3_000_002.0).abs() < 1e-12);
     }
 }
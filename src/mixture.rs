@@ -0,0 +1,260 @@
+use rand::prelude::*;
+
+use std::collections::HashMap;
+
+use casing::CaseTable;
+use csv_parser::Level;
+use markovchain::{LineEnd, LineModel, MarkovChain, END_TOKEN, MAX_LENGTH, START_TOKEN};
+use sampling::{self, GenerationConfig};
+use serialize::errors::{Error, Result};
+use smoothing::Smoothing;
+
+/// Several chains sampled together: the probability of the next token is
+/// the weighted sum of its probabilities under every chain.
+pub struct MixtureChain {
+    /// How tokens are picked, shared by all the chains.
+    pub config: GenerationConfig,
+    level: Level,
+    /// The chains with their share of the probability.
+    models: Vec<(MarkovChain, f64)>,
+    /// Union of the vocabularies of the chains.
+    tokens: Vec<String>,
+    /// Id in each chain of every token of the union, `START_TOKEN` when the
+    /// chain doesn't know it so no context can match.
+    ids: Vec<Vec<i32>>,
+    /// Id in the union of every token of each chain.
+    union_ids: Vec<Vec<i32>>,
+    /// Casing counts of all the chains, by union id.
+    casing: CaseTable,
+}
+
+impl MixtureChain {
+    /// Mix chains of the same level, with weights that don't have to add up
+    /// to 1.
+    pub fn new(models: Vec<(MarkovChain, f64)>) -> Result<MixtureChain> {
+        let level = match models.first() {
+            Some((chain, _)) => chain.level,
+            None => return Err(Error::new_incompatible_chains("no chain to mix")),
+        };
+        if models.iter().any(|(chain, _)| chain.level != level) {
+            return Err(Error::new_incompatible_chains(
+                "words and characters can't be mixed",
+            ));
+        }
+        if models.iter().any(|(_, weight)| *weight <= 0.0) {
+            return Err(Error::new_incompatible_chains("weights must be positive"));
+        }
+
+        let total: f64 = models.iter().map(|(_, weight)| weight).sum();
+        let models: Vec<(MarkovChain, f64)> = models
+            .into_iter()
+            .map(|(chain, weight)| (chain, weight / total))
+            .collect();
+
+        let mut tokens: Vec<String> = Vec::new();
+        let mut index: HashMap<&str, i32> = HashMap::new();
+        let mut union_ids: Vec<Vec<i32>> = Vec::with_capacity(models.len());
+        for (chain, _) in models.iter() {
+            let mut chain_ids = Vec::with_capacity(chain.tokens.len());
+            for token in chain.tokens.iter() {
+                let id = *index.entry(token).or_insert_with(|| {
+                    tokens.push(token.clone());
+                    tokens.len() as i32 - 1
+                });
+                chain_ids.push(id);
+            }
+            union_ids.push(chain_ids);
+        }
+
+        let mut ids: Vec<Vec<i32>> = vec![vec![START_TOKEN; tokens.len()]; models.len()];
+        let mut casing = CaseTable::new();
+        for (model, chain_ids) in union_ids.iter().enumerate() {
+            for (id, union_id) in chain_ids.iter().enumerate() {
+                ids[model][*union_id as usize] = id as i32;
+            }
+
+            casing.merge_remapped(&models[model].0.casing, |id| Some(chain_ids[id as usize]));
+        }
+
+        Ok(MixtureChain {
+            config: GenerationConfig::default(),
+            level,
+            models,
+            tokens,
+            ids,
+            union_ids,
+            casing,
+        })
+    }
+
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+        for (chain, _) in self.models.iter_mut() {
+            chain.set_smoothing(smoothing);
+        }
+    }
+
+    /// Whether one of the chains has a novelty index.
+    pub fn has_novelty(&self) -> bool {
+        self.models.iter().any(|(chain, _)| chain.novelty.is_some())
+    }
+
+    /// Weight of every token of the union, or `END_TOKEN`, after `history`,
+    /// under what the length constraints say about ending the line there.
+    fn next_weights(&self, history: &[i32], line_end: LineEnd) -> Vec<(i32, f64)> {
+        let mut weights: HashMap<i32, f64> = HashMap::new();
+        for (model, (chain, weight)) in self.models.iter().enumerate() {
+            let chain_history: Vec<i32> = history
+                .iter()
+                .map(|id| self.ids[model][*id as usize])
+                .collect();
+            for (id, probability) in chain.steered_distribution(&chain_history, line_end) {
+                let union_id = if id == END_TOKEN {
+                    END_TOKEN
+                } else {
+                    self.union_ids[model][id as usize]
+                };
+                *weights.entry(union_id).or_insert(0.0) += weight * probability;
+            }
+        }
+
+        weights.into_iter().collect()
+    }
+
+    /// Walk the mixture until the end of a line.
+    fn walk<R: Rng>(&self, rng: &mut R) -> Vec<i32> {
+        let mut line: Vec<i32> = Vec::new();
+        let mut length = 0;
+        let mut words = 0;
+        while length < MAX_LENGTH {
+            let line_end = self.line_end(&line, words);
+            let mut weights = self.next_weights(&line, line_end);
+            line_end.steer(&mut weights);
+            match sampling::sample(weights, &self.config, rng) {
+                Some(END_TOKEN) | None => break,
+                Some(id) => {
+                    if self.starts_word(line.last().cloned(), id) {
+                        words += 1;
+                    }
+                    length += self.token_length(id);
+                    line.push(id);
+                }
+            }
+        }
+
+        line
+    }
+
+    /// Generate a oneliner following the config, retrying up to `MAX_TRIES`
+    /// times to pass its length constraints and novelty filter.
+    pub fn generate_with_rng<R: Rng>(&self, rng: &mut R) -> Result<String> {
+        self.filtered_line(rng, |rng| self.walk(rng))
+    }
+}
+
+impl LineModel for MixtureChain {
+    fn level(&self) -> Level {
+        self.level
+    }
+
+    fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    fn casing(&self) -> &CaseTable {
+        &self.casing
+    }
+
+    fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    /// Whether a line copies too much of the training lines of any chain.
+    fn is_copy(&self, line: &[i32]) -> bool {
+        let tokens: Vec<&str> = line
+            .iter()
+            .map(|id| self.tokens[*id as usize].as_str())
+            .collect();
+        self.models.iter().any(|(chain, _)| match chain.novelty {
+            Some(ref index) => self.config.is_copy(index, &tokens),
+            None => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv_parser::get_words;
+    use rand::rngs::StdRng;
+
+    fn chain(lines: &[&str]) -> MarkovChain {
+        let mut chain = MarkovChain::new(2);
+        for line in lines.iter() {
+            get_words(&mut chain, line);
+        }
+        chain
+    }
+
+    #[test]
+    fn weights_split_the_probability() {
+        let amiga = chain(&["long live amiga"]);
+        let atari = chain(&["long live atari", "atari rules"]);
+        let mixture = MixtureChain::new(vec![(amiga, 3.0), (atari, 1.0)]).unwrap();
+
+        let id = |word: &str| mixture.tokens.iter().position(|t| t == word).unwrap() as i32;
+        let weight = |weights: &[(i32, f64)], word: &str| {
+            weights.iter().find(|item| item.0 == id(word)).unwrap().1
+        };
+
+        let first = mixture.next_weights(&[], LineEnd::Free);
+        assert!((weight(&first, "long") - 0.875).abs() < 1e-9);
+        assert!((weight(&first, "atari") - 0.125).abs() < 1e-9);
+
+        let after = mixture.next_weights(&[id("long"), id("live")], LineEnd::Free);
+        assert!((weight(&after, "amiga") - 0.75).abs() < 1e-9);
+        assert!((weight(&after, "atari") - 0.25).abs() < 1e-9);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let line = mixture.generate_with_rng(&mut rng).unwrap();
+            let seen = [
+                "long live amiga",
+                "long live atari",
+                "long live atari rules",
+                "atari rules",
+                "atari",
+            ];
+            assert!(seen.contains(&line.as_str()));
+        }
+    }
+
+    #[test]
+    fn short_lines_go_on_when_they_can() {
+        let mut lines = vec!["amiga"; 99];
+        lines.push("amiga rules forever");
+        let mut mixture = MixtureChain::new(vec![(chain(&lines), 1.0)]).unwrap();
+        mixture.config.min_words = Some(3);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let line = mixture.generate_with_rng(&mut rng).unwrap();
+            assert_eq!(line, "amiga rules forever");
+        }
+    }
+
+    #[test]
+    fn characters_use_the_whole_length() {
+        let mut amiga = MarkovChain::new(2);
+        amiga.level = Level::Char;
+        get_words(&mut amiga, &"xyz".repeat(100));
+        let mixture = MixtureChain::new(vec![(amiga, 1.0)]).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let longest = (0..20)
+            .map(|_| mixture.generate_with_rng(&mut rng).unwrap().len())
+            .max()
+            .unwrap();
+        assert!(longest > MAX_LENGTH / 2);
+        assert!(longest <= MAX_LENGTH);
+    }
+}
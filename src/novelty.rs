@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+
+/// Compact index of the training lines: the hash of every run of
+/// `shingle_size` words, or of the whole line when it is shorter.
+pub struct NoveltyIndex {
+    pub shingle_size: usize,
+    pub shingles: HashSet<i32>,
+}
+
+/// 32 bits FNV-1a of words separated by spaces. Unlike the std hasher it
+/// doesn't change between runs or Rust versions, so it can be saved.
+fn hash(words: &[&str]) -> i32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            hash ^= u32::from(b' ');
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        for byte in word.bytes() {
+            hash ^= u32::from(byte);
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+    }
+    hash as i32
+}
+
+impl NoveltyIndex {
+    pub fn new(shingle_size: usize) -> NoveltyIndex {
+        NoveltyIndex {
+            shingle_size,
+            shingles: HashSet::new(),
+        }
+    }
+
+    fn windows<'a>(&self, words: &'a [&'a str]) -> Vec<&'a [&'a str]> {
+        if words.len() < self.shingle_size {
+            vec![words]
+        } else {
+            words.windows(self.shingle_size).collect()
+        }
+    }
+
+    pub fn add_line(&mut self, words: &[&str]) {
+        for window in self.windows(words) {
+            self.shingles.insert(hash(window));
+        }
+    }
+
+    /// Whether each word of a line is part of a run of words seen in training.
+    fn copied(&self, words: &[&str]) -> Vec<bool> {
+        let mut copied = vec![false; words.len()];
+        let size = self.shingle_size.min(words.len());
+        for (i, window) in self.windows(words).iter().enumerate() {
+            if self.shingles.contains(&hash(window)) {
+                for word in copied.iter_mut().skip(i).take(size) {
+                    *word = true;
+                }
+            }
+        }
+        copied
+    }
+
+    /// Length, in words, of the longest part of a line copied from training.
+    pub fn longest_copy(&self, words: &[&str]) -> usize {
+        let mut longest = 0;
+        let mut current = 0;
+        for copied in self.copied(words) {
+            current = if copied { current + 1 } else { 0 };
+            longest = longest.max(current);
+        }
+        longest
+    }
+
+    /// Share of the words of a line that are copied from training.
+    pub fn overlap(&self, words: &[&str]) -> f64 {
+        if words.is_empty() {
+            return 0.0;
+        }
+
+        let copied = self.copied(words).into_iter().filter(|c| *c).count();
+        copied as f64 / words.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copied_runs_are_measured() {
+        let mut index = NoveltyIndex::new(3);
+        index.add_line(&["greetings", "to", "all", "the", "sceners"]);
+        index.add_line(&["hi", "all"]);
+
+        let line = ["greetings", "to", "all", "the", "coders"];
+        assert_eq!(index.longest_copy(&line), 4);
+        assert!((index.overlap(&line) - 0.8).abs() < 1e-9);
+
+        assert_eq!(index.longest_copy(&["hi", "all"]), 2);
+        assert_eq!(index.longest_copy(&["hi", "there"]), 0);
+    }
+}
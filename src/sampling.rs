@@ -0,0 +1,257 @@
+use rand::prelude::*;
+
+use casing::Case;
+use novelty::NoveltyIndex;
+
+use std::cmp::Ordering;
+
+/// Controls how the next token is picked among the possible ones.
+#[derive(Clone, Debug)]
+pub struct GenerationConfig {
+    /// Below 1 the likely tokens get even more likely, above 1 the
+    /// distribution gets flatter.
+    pub temperature: f64,
+    /// Only sample among the `k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Only sample among the most likely tokens whose probabilities add up
+    /// to at least `p` (nucleus sampling).
+    pub top_p: Option<f64>,
+    /// Always pick the most likely token.
+    pub greedy: bool,
+    /// Retry lines copying more than this many consecutive words from the
+    /// training lines. Needs a novelty index.
+    pub max_copy: Option<usize>,
+    /// Retry lines with a bigger share of words copied from the training
+    /// lines. Needs a novelty index.
+    pub max_overlap: Option<f64>,
+    /// Bounds on the number of words of a line, punctuation excluded.
+    pub min_words: Option<usize>,
+    pub max_words: Option<usize>,
+    /// Bounds on the number of characters of a line.
+    pub min_chars: Option<usize>,
+    pub max_chars: Option<usize>,
+    /// How the case of the lines is restored.
+    pub case: Case,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> GenerationConfig {
+        GenerationConfig {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            greedy: false,
+            max_copy: None,
+            max_overlap: None,
+            min_words: None,
+            max_words: None,
+            min_chars: None,
+            max_chars: None,
+            case: Case::default(),
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Whether tokens are sampled straight from their weights.
+    pub fn is_plain(&self) -> bool {
+        !self.greedy
+            && (self.temperature - 1.0).abs() <= f64::EPSILON
+            && self.top_k.is_none()
+            && self.top_p.is_none()
+    }
+
+    /// Whether a line of this many words and characters has the length
+    /// asked.
+    pub fn fits(&self, words: usize, chars: usize) -> bool {
+        self.min_words.is_none_or(|min| words >= min)
+            && self.max_words.is_none_or(|max| words <= max)
+            && self.min_chars.is_none_or(|min| chars >= min)
+            && self.max_chars.is_none_or(|max| chars <= max)
+    }
+
+    /// Whether the tokens of a line copy too much of the lines of `index`.
+    pub fn is_copy(&self, index: &NoveltyIndex, tokens: &[&str]) -> bool {
+        let too_long = match self.max_copy {
+            Some(max) => index.longest_copy(tokens) > max,
+            None => false,
+        };
+        let too_similar = match self.max_overlap {
+            Some(max) => index.overlap(tokens) > max,
+            None => false,
+        };
+        too_long || too_similar
+    }
+}
+
+/// Walker's alias table, to sample a weighted token in constant time.
+pub struct AliasTable {
+    tokens: Vec<i32>,
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    /// Build the table from `(token, weight)` pairs, using Vose's method.
+    pub fn new(weights: &[(i32, f64)]) -> AliasTable {
+        let len = weights.len();
+        let total: f64 = weights.iter().map(|item| item.1).sum();
+        let mut scaled: Vec<f64> = weights
+            .iter()
+            .map(|item| item.1 * len as f64 / total)
+            .collect();
+
+        let mut small: Vec<usize> = Vec::new();
+        let mut large: Vec<usize> = Vec::new();
+        for (i, p) in scaled.iter().enumerate() {
+            if *p < 1.0 {
+                small.push(i);
+            } else {
+                large.push(i);
+            }
+        }
+
+        // Whatever is left once a list is empty is only there because of
+        // rounding errors and keeps a probability of 1.
+        let mut prob: Vec<f64> = vec![1.0; len];
+        let mut alias: Vec<usize> = (0..len).collect();
+        while !small.is_empty() && !large.is_empty() {
+            let less = small.pop().unwrap();
+            let more = large.pop().unwrap();
+            prob[less] = scaled[less];
+            alias[less] = more;
+
+            scaled[more] += scaled[less] - 1.0;
+            if scaled[more] < 1.0 {
+                small.push(more);
+            } else {
+                large.push(more);
+            }
+        }
+
+        AliasTable {
+            tokens: weights.iter().map(|item| item.0).collect(),
+            prob,
+            alias,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> i32 {
+        let i = rng.gen_range(0, self.tokens.len());
+        if rng.gen::<f64>() < self.prob[i] {
+            self.tokens[i]
+        } else {
+            self.tokens[self.alias[i]]
+        }
+    }
+}
+
+/// Pick a token from `(token, weight)` pairs according to the config.
+pub fn sample<R: Rng>(
+    mut weights: Vec<(i32, f64)>,
+    config: &GenerationConfig,
+    rng: &mut R,
+) -> Option<i32> {
+    weights.retain(|item| item.1 > 0.0);
+    if weights.is_empty() {
+        return None;
+    }
+
+    // Most likely first, ties broken by id so a seeded rng stays reproducible.
+    weights.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(Ordering::Equal)
+            .then(a.0.cmp(&b.0))
+    });
+
+    if config.greedy || config.temperature <= 0.0 {
+        return Some(weights[0].0);
+    }
+
+    if (config.temperature - 1.0).abs() > f64::EPSILON {
+        // Scale by the biggest weight first so small weights don't underflow.
+        let max = weights[0].1;
+        for item in weights.iter_mut() {
+            item.1 = (item.1 / max).powf(1.0 / config.temperature);
+        }
+    }
+
+    if let Some(k) = config.top_k {
+        weights.truncate(k.max(1));
+    }
+
+    if let Some(p) = config.top_p {
+        let total: f64 = weights.iter().map(|item| item.1).sum();
+        let mut cumulated = 0.0;
+        let mut keep = 0;
+        for item in weights.iter() {
+            cumulated += item.1 / total;
+            keep += 1;
+            if cumulated >= p {
+                break;
+            }
+        }
+        weights.truncate(keep);
+    }
+
+    Some(weights.choose_weighted(rng, |item| item.1).unwrap().0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+
+    fn weights() -> Vec<(i32, f64)> {
+        vec![(0, 0.1), (1, 0.6), (2, 0.3)]
+    }
+
+    #[test]
+    fn greedy_and_top_k_one_pick_the_most_likely() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let greedy = GenerationConfig {
+            greedy: true,
+            ..Default::default()
+        };
+        let top_k = GenerationConfig {
+            top_k: Some(1),
+            ..Default::default()
+        };
+
+        for _ in 0..20 {
+            assert_eq!(sample(weights(), &greedy, &mut rng), Some(1));
+            assert_eq!(sample(weights(), &top_k, &mut rng), Some(1));
+        }
+    }
+
+    #[test]
+    fn alias_table_follows_the_weights() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let table = AliasTable::new(&weights());
+
+        let mut counts = [0; 3];
+        for _ in 0..10000 {
+            counts[table.sample(&mut rng) as usize] += 1;
+        }
+        assert!((800..1200).contains(&counts[0]));
+        assert!((5600..6400).contains(&counts[1]));
+        assert!((2600..3400).contains(&counts[2]));
+    }
+
+    #[test]
+    fn top_p_drops_the_tail() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let config = GenerationConfig {
+            top_p: Some(0.8),
+            ..Default::default()
+        };
+
+        for _ in 0..50 {
+            assert_ne!(sample(weights(), &config, &mut rng), Some(0));
+        }
+    }
+}
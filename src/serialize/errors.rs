@@ -5,7 +5,7 @@ use std::str::Utf8Error;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-type Cause = Box<StdError + Send + Sync>;
+type Cause = Box<dyn StdError + Send + Sync>;
 
 pub struct Error {
     inner: Box<ErrorImpl>,
@@ -18,12 +18,15 @@ struct ErrorImpl {
 
 #[derive(Debug)]
 pub enum ErrorKind {
-    SerializeError,
     UnserializeError,
     NotEnoughBytes,
     TooMuchBytes,
     Io,
     StringError,
+    UnknownToken,
+    IncompatibleChains,
+    UnsupportedFormat,
+    NoLineFound,
 }
 
 impl Error {
@@ -33,10 +36,6 @@ impl Error {
         }
     }
 
-    pub fn new_serialize() -> Error {
-        Error::new(ErrorKind::SerializeError, None)
-    }
-
     pub fn new_unserialize() -> Error {
         Error::new(ErrorKind::UnserializeError, None)
     }
@@ -57,8 +56,40 @@ impl Error {
         Error::new(ErrorKind::StringError, Some(err.into()))
     }
 
-    pub fn into_cause(self) -> Option<Box<StdError + Sync + Send>> {
-        self.inner.cause
+    pub fn new_unknown_token(word: &str) -> Error {
+        Error::new(
+            ErrorKind::UnknownToken,
+            Some(format!("\"{}\"", word).into()),
+        )
+    }
+
+    pub fn new_incompatible_chains(why: &str) -> Error {
+        Error::new(ErrorKind::IncompatibleChains, Some(why.into()))
+    }
+
+    pub fn new_unsupported_format(why: &str) -> Error {
+        Error::new(ErrorKind::UnsupportedFormat, Some(why.into()))
+    }
+
+    pub fn new_no_line_found(tries: usize) -> Error {
+        Error::new(
+            ErrorKind::NoLineFound,
+            Some(format!("none of {} tries passed the filters", tries).into()),
+        )
+    }
+
+    fn message(&self) -> &str {
+        match self.inner.kind {
+            ErrorKind::UnserializeError => "Impossible to unserialize.",
+            ErrorKind::NotEnoughBytes => "Provided not enough bytes to serialize this type.",
+            ErrorKind::TooMuchBytes => "Provided too much bytes to serialize this type.",
+            ErrorKind::Io => "I/O Error",
+            ErrorKind::StringError => "String Error",
+            ErrorKind::UnknownToken => "Word not in the vocabulary",
+            ErrorKind::IncompatibleChains => "Chains can't be combined",
+            ErrorKind::UnsupportedFormat => "Unsupported binary file",
+            ErrorKind::NoLineFound => "No line found",
+        }
     }
 }
 
@@ -76,27 +107,23 @@ impl fmt::Debug for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if let Some(ref cause) = self.inner.cause {
-            write!(f, "{}: {}", self.description(), cause)
+            write!(f, "{}: {}", self.message(), cause)
         } else {
-            f.write_str(self.description())
+            f.write_str(self.message())
         }
     }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
-        match self.inner.kind {
-            ErrorKind::SerializeError => "Impossible to serialize.",
-            ErrorKind::UnserializeError => "Impossible to unserialize.",
-            ErrorKind::NotEnoughBytes => "Provided not enough bytes to serialize this type.",
-            ErrorKind::TooMuchBytes => "Provided too much bytes to serialize this type.",
-            ErrorKind::Io => "I/O Error",
-            ErrorKind::StringError => "String Error",
-        }
+        self.message()
     }
 
-    fn cause(&self) -> Option<&StdError> {
-        self.inner.cause.as_ref().map(|cause| &**cause as &StdError)
+    fn cause(&self) -> Option<&dyn StdError> {
+        self.inner
+            .cause
+            .as_ref()
+            .map(|cause| &**cause as &dyn StdError)
     }
 }
 
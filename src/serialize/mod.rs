@@ -10,13 +10,12 @@ pub trait Serializable {
 }
 
 pub trait Unserializable<T: Sized> {
-    fn unserialize(&[u8]) -> Result<T>;
+    fn unserialize(bytes: &[u8]) -> Result<T>;
 }
 
 impl Serializable for i32 {
     fn serialize(&self) -> Result<Vec<u8>> {
-        let value = unsafe { mem::transmute::<i32, [u8; 4]>(self.to_le()).to_vec() };
-        Ok(value)
+        Ok(self.to_le_bytes().to_vec())
     }
 }
 
@@ -28,10 +27,27 @@ impl Unserializable<i32> for i32 {
             return Err(Error::new_not_enough_bytes());
         }
 
-        let value = unsafe {
-            mem::transmute::<[u8; 4], i32>([bytes[0], bytes[1], bytes[2], bytes[3]]).to_le()
-        };
-        Ok(value)
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl Serializable for i64 {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.to_le_bytes().to_vec())
+    }
+}
+
+impl Unserializable<i64> for i64 {
+    fn unserialize(bytes: &[u8]) -> Result<i64> {
+        if bytes.len() > 8 {
+            return Err(Error::new_too_much_bytes());
+        } else if bytes.len() < 8 {
+            return Err(Error::new_not_enough_bytes());
+        }
+
+        let mut buf: [u8; 8] = [0; 8];
+        buf.copy_from_slice(bytes);
+        Ok(i64::from_le_bytes(buf))
     }
 }
 
@@ -0,0 +1,70 @@
+use std::str::FromStr;
+
+/// Default add-k constant.
+const DEFAULT_K: f64 = 1.0;
+
+/// Default absolute discount of Kneser-Ney.
+const DEFAULT_DISCOUNT: f64 = 0.75;
+
+/// How the transition counts are turned into probabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Smoothing {
+    /// Relative frequencies with "stupid backoff". Unseen transitions are
+    /// never sampled.
+    #[default]
+    Backoff,
+    /// Add `k` to every count of the longest seen context (Laplace for 1).
+    Additive(f64),
+    /// Interpolate each context with the shorter one, giving the shorter one
+    /// a weight growing with the number of distinct followers.
+    WittenBell,
+    /// Interpolated Kneser-Ney with an absolute discount.
+    KneserNey(f64),
+}
+
+impl FromStr for Smoothing {
+    type Err = String;
+
+    /// Parse `backoff`, `additive[:k]`, `witten-bell` or `kneser-ney[:discount]`.
+    fn from_str(s: &str) -> ::std::result::Result<Smoothing, String> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let param = match parts.next() {
+            Some(param) => Some(
+                param
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid smoothing parameter: {}", param))?,
+            ),
+            None => None,
+        };
+
+        match name {
+            "backoff" => Ok(Smoothing::Backoff),
+            "additive" | "laplace" => Ok(Smoothing::Additive(param.unwrap_or(DEFAULT_K))),
+            "witten-bell" => Ok(Smoothing::WittenBell),
+            "kneser-ney" => Ok(Smoothing::KneserNey(param.unwrap_or(DEFAULT_DISCOUNT))),
+            _ => Err(format!("Unknown smoothing: {}", name)),
+        }
+    }
+}
+
+/// Add-k estimate of a token seen `count` times among `total` transitions,
+/// with `outcomes` possible tokens.
+pub fn additive(count: f64, total: f64, k: f64, outcomes: usize) -> f64 {
+    (count + k) / (total + k * outcomes as f64)
+}
+
+/// Witten-Bell estimate of a token seen `count` times among `total`
+/// transitions to `distinct` different tokens, `lower` being its probability
+/// under the shorter context.
+pub fn witten_bell(count: f64, total: f64, distinct: usize, lower: f64) -> f64 {
+    let distinct = distinct as f64;
+    (count + distinct * lower) / (total + distinct)
+}
+
+/// Interpolated Kneser-Ney estimate, same arguments as `witten_bell` plus the
+/// absolute discount.
+pub fn kneser_ney(count: f64, total: f64, distinct: usize, discount: f64, lower: f64) -> f64 {
+    let kept = (count - discount).max(0.0) / total;
+    kept + discount * distinct as f64 / total * lower
+}